@@ -0,0 +1,191 @@
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::battery::BatteryData;
+use crate::database::Database;
+use crate::error::Result;
+use crate::estimator::{self, Estimator, Mean};
+use crate::heartbeat;
+use crate::human_display::HumanDuration;
+
+/// Draining this many times faster than usual is abnormal
+const ANOMALY_FACTOR: f64 = 3.0;
+
+/// How many of the last percents the current drain rate is measured over
+const WINDOW_PERCENTS: usize = 5;
+
+/// Measurements needed, outside the window, before the usual drain rate is trusted
+const MIN_BASELINE_MEASUREMENTS: usize = 10;
+
+const KIND: &str = "abnormal_drain";
+
+/// A device draining much faster than it usually does, like a stuck awake state would
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Anomaly {
+    pub device_id: String,
+    /// How many times faster than usual
+    pub factor: f64,
+    /// Usual seconds per percent
+    pub baseline_seconds: i64,
+    /// Seconds per percent over the last few percents
+    pub recent_seconds: i64,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "draining {:.1}× faster than usual, 1% every {} instead of {}",
+            self.factor,
+            Duration::seconds(self.recent_seconds).as_human(),
+            Duration::seconds(self.baseline_seconds).as_human()
+        )
+    }
+}
+
+/// Compares the drain over the last percents of the ongoing discharge against the device's usual
+/// drain, estimated from the rest of its history
+pub(crate) fn detect(
+    entries: &[BatteryData],
+    gaps: &[heartbeat::Gap],
+    estimator: &dyn Estimator,
+) -> Option<Anomaly> {
+    let last = entries.last()?;
+    if last.charging {
+        return None;
+    }
+    let start = discharge_start(entries);
+    let recent = BatteryData::measurements(&entries[start..], gaps);
+    if recent.len() < WINDOW_PERCENTS {
+        return None;
+    }
+    let window = &recent[recent.len() - WINDOW_PERCENTS..];
+
+    let measurements = BatteryData::measurements(entries, gaps);
+    let baseline = &measurements[..measurements.len().saturating_sub(WINDOW_PERCENTS)];
+    if baseline.len() < MIN_BASELINE_MEASUREMENTS {
+        return None;
+    }
+    let baseline_seconds = estimator.estimate(baseline)?;
+    let recent_seconds = Mean.estimate(window)?.max(1);
+    let factor = baseline_seconds as f64 / recent_seconds as f64;
+    (factor >= ANOMALY_FACTOR).then(|| Anomaly {
+        device_id: last.device_id.clone(),
+        factor,
+        baseline_seconds,
+        recent_seconds,
+    })
+}
+
+/// Index of the first sample of the ongoing discharge
+fn discharge_start(entries: &[BatteryData]) -> usize {
+    entries
+        .iter()
+        .rposition(|e| e.charging)
+        .map_or(0, |index| index + 1)
+}
+
+/// Looks for an abnormal drain on a device, recording it in the `events` table
+///
+/// An anomaly is only reported once per discharge, so a device left draining isn't reported over
+/// and over.
+pub(crate) fn check(db: &Database, device_id: &str, now: DateTime<Utc>) -> Result<Option<Anomaly>> {
+    let entries = BatteryData::get(db, device_id)?;
+    let estimator = estimator::load(db)?;
+    let Some(anomaly) = detect(&entries, &heartbeat::gaps(db)?, estimator.as_ref()) else {
+        return Ok(None);
+    };
+    let since = entries[discharge_start(&entries)].created_at;
+    let reported = db.with(|conn| {
+        let reported: bool = conn
+            .prepare_cached(
+                "SELECT EXISTS(SELECT 1 FROM events WHERE device_id = ?1 AND kind = ?2 AND created_at >= ?3)",
+            )?
+            .query_row((device_id, KIND, since.timestamp_millis()), |row| row.get(0))?;
+        Ok(reported)
+    })?;
+    if reported {
+        return Ok(None);
+    }
+    db.with(|conn| {
+        conn.prepare_cached(
+            "INSERT INTO events (device_id, created_at, kind, factor, message) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?
+        .execute((
+            device_id,
+            now.timestamp_millis(),
+            KIND,
+            anomaly.factor,
+            anomaly.to_string(),
+        ))?;
+        Ok(())
+    })?;
+    Ok(Some(anomaly))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::BatteryStatus;
+
+    /// A day losing 1% an hour, then a charge, then a discharge losing 1% every `minutes`
+    fn entries(minutes: i64, percents: u8) -> Vec<BatteryData> {
+        let start = BatteryData::new(1, "2022-01-01 00:00:00", 0, false).created_at;
+        let entry = |offset: Duration, percentage: u8, charging: bool| BatteryData {
+            created_at: start + offset,
+            ..BatteryData::new(1, "2022-01-01 00:00:00", percentage, charging)
+        };
+        let mut entries = (0..=20)
+            .map(|hour| entry(Duration::hours(hour), 100 - hour as u8, false))
+            .collect::<Vec<_>>();
+        entries.push(entry(Duration::days(1), 80, true));
+        for percent in 0..=percents {
+            let offset = Duration::hours(25) + Duration::minutes(minutes * percent as i64);
+            entries.push(entry(offset, 100 - percent, false));
+        }
+        entries
+    }
+
+    #[test]
+    fn test_detect() {
+        let anomaly = detect(&entries(10, 5), &[], &Mean).unwrap();
+        assert_eq!(anomaly.factor, 6.0);
+        assert_eq!(anomaly.baseline_seconds, 3600);
+        assert_eq!(anomaly.recent_seconds, 600);
+        assert_eq!(
+            anomaly.to_string(),
+            "draining 6.0× faster than usual, 1% every 10m instead of 1h"
+        );
+
+        // usual drain
+        assert_eq!(detect(&entries(50, 5), &[], &Mean), None);
+        // not enough percents lost yet
+        assert_eq!(detect(&entries(10, 4), &[], &Mean), None);
+        // not enough history
+        assert_eq!(detect(&entries(10, 5)[15..], &[], &Mean), None);
+    }
+
+    #[test]
+    fn test_check_reports_once() {
+        let db = Database::open_in_memory().unwrap();
+        for entry in entries(10, 5) {
+            let status = BatteryStatus {
+                device_id: entry.device_id.clone(),
+                product_id: entry.product_id,
+                name: "Mouse".to_string(),
+                percentage: entry.percentage,
+                charging: entry.charging,
+            };
+            status.save_at(&db, entry.created_at).unwrap();
+        }
+        let now = BatteryData::new(1, "2022-01-02 02:00:00", 0, false).created_at;
+        assert!(check(&db, "0001:0", now).unwrap().is_some());
+        assert_eq!(check(&db, "0001:0", now).unwrap(), None);
+
+        let events: i64 = db
+            .with(|conn| Ok(conn.query_row("SELECT COUNT(*) FROM events", (), |row| row.get(0))?))
+            .unwrap();
+        assert_eq!(events, 1);
+    }
+}
@@ -1,101 +1,146 @@
 use std::fmt;
 
+use crate::curve::{self, Prediction};
+use crate::cycles::ChargeCycles;
+use crate::device::{DeviceInfo, DeviceSource};
+use crate::estimator::{self, Estimator, Mean, Measurement};
+use crate::heartbeat::{self, Gap};
 use crate::human_display::HumanDuration;
-use chrono::{Duration, NaiveDateTime};
-use razer_driver_rs::{
-    razer_device::{RazerDevice, RazerDeviceType},
-    scan_for_devices,
-};
+use crate::pattern;
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone, Utc};
 use rusqlite::OptionalExtension;
 
-use crate::database;
+use crate::database::Database;
+use crate::error::Result;
 
 #[derive(Clone, Debug, serde::Serialize)]
 pub(crate) struct BatteryStatus {
+    pub device_id: String,
     pub product_id: u16,
     pub name: String,
     pub percentage: u8,
     pub charging: bool,
 }
 
+/// A device's status along with how much it has been used, as the frontend shows it
+#[derive(Clone, Debug, serde::Serialize)]
+pub(crate) struct DeviceStatus {
+    #[serde(flatten)]
+    pub status: BatteryStatus,
+    pub cycles: ChargeCycles,
+}
+
 impl BatteryStatus {
-    pub fn get(product_id: u16) -> anyhow::Result<Option<Self>> {
-        let found = scan_for_devices(Some(product_id))?;
-        let Some(device) = found.devices.get(0) else {
+    pub fn get(source: &dyn DeviceSource, device_id: &str) -> Result<Option<Self>> {
+        let Some(device) = source.identify(device_id)? else {
             return Ok(None);
         };
-
-        let battery = device.get_battery_charge().unwrap();
-        let percentage = (battery as f32 / 255.0 * 100.0).round() as u8;
-        let charging = device.get_charging_status().unwrap() == 1;
-
-        Ok(Some(BatteryStatus {
-            product_id,
-            name: device.name.clone(),
-            percentage,
-            charging,
-        }))
+        Self::read(source, &device).map(Some)
     }
 
     pub fn get_from_devices(
-        devices: &Vec<RazerDevice<RazerDeviceType>>,
-        product_id: u16,
-    ) -> anyhow::Result<Option<Self>> {
-        let Some(device) = devices.into_iter().find(|d| d.device.product_id() == product_id) else {
+        source: &dyn DeviceSource,
+        devices: &[DeviceInfo],
+        device_id: &str,
+    ) -> Result<Option<Self>> {
+        let Some(device) = devices.iter().find(|d| d.id == device_id) else {
             return Ok(None);
         };
-        let battery = device.get_battery_charge().unwrap();
-        let percentage = (battery as f32 / 255.0 * 100.0).round() as u8;
-        let charging = device.get_charging_status().unwrap() == 1;
+        Self::read(source, device).map(Some)
+    }
 
-        Ok(Some(BatteryStatus {
-            product_id: device.device.product_id(),
+    pub fn read(source: &dyn DeviceSource, device: &DeviceInfo) -> Result<Self> {
+        let percentage = source.battery_charge(device)?;
+        let charging = source.charging_status(device)?;
+
+        Ok(BatteryStatus {
+            device_id: device.id.clone(),
+            product_id: device.product_id,
             name: device.name.clone(),
             percentage,
             charging,
-        }))
+        })
+    }
+
+    pub fn last_status(db: &Database, device_id: &str) -> Result<Option<u8>> {
+        db.with(|conn| {
+            let percentage: Option<u8> = conn.prepare_cached(
+                "SELECT percentage FROM battery WHERE percentage > 0 AND device_id = ?1 ORDER BY created_at DESC LIMIT 1",
+            )?
+            .query_row([device_id], |row| row.get::<usize, u8>(0))
+            .optional()?;
+            Ok(percentage)
+        })
     }
 
-    pub fn last_status(product_id: u16) -> anyhow::Result<Option<u8>> {
-        let db = database::Conn::new()?;
-        let percentage: Option<u8> = db.conn.query_row(
-            "SELECT percentage FROM battery WHERE percentage > 0 AND product_id = ?1 ORDER BY created_at DESC LIMIT 1",
-            [&product_id],
-            |row| row.get::<usize, u8>(0),
-        ).optional()?;
-        Ok(percentage)
+    /// Records the status as read now
+    pub fn save(&self, db: &Database) -> Result<()> {
+        self.save_at(db, Utc::now())
     }
 
-    pub fn save(&self) -> anyhow::Result<()> {
-        let db = database::Conn::new()?;
+    pub fn save_at(&self, db: &Database, created_at: DateTime<Utc>) -> Result<()> {
         let charging = if self.charging { 1 } else { 0 };
-        db.conn.execute(
-            "INSERT INTO battery (product_id, percentage, charging) VALUES (?1, ?2, ?3)",
-            (&self.product_id, &self.percentage, &charging),
-        )?;
-        Ok(())
-    }
-
-    pub fn remaining(&self) -> anyhow::Result<Option<Duration>> {
-        let entries = BatteryData::get(self.product_id)?;
-        let consumption = BatteryData::consumption(&entries);
-        match consumption {
-            Some(consumption) => Ok(Some(Duration::seconds(
-                consumption * self.percentage as i64,
-            ))),
-            None => Ok(None),
+        db.with(|conn| {
+            conn.prepare_cached(
+                "INSERT INTO battery (created_at, device_id, product_id, percentage, charging) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute((
+                created_at.timestamp_millis(),
+                &self.device_id,
+                &self.product_id,
+                &self.percentage,
+                &charging,
+            ))?;
+            Ok(())
+        })
+    }
+
+    /// How long the battery is expected to last, following the device's discharge curve, along
+    /// with how sure that is
+    ///
+    /// Once the device's weekly pattern is known, the drain the curve expects is spread over the
+    /// week the way the pattern says from now on, so the nights and weekends it usually spends
+    /// idle are accounted for.
+    pub fn remaining(&self, db: &Database) -> Result<Option<Prediction>> {
+        let curve = curve::load(db, &self.device_id)?;
+        let Some(prediction) = curve.predict(self.percentage, 0) else {
+            return Ok(None);
+        };
+        let pattern = pattern::load(db, &self.device_id)?;
+        let seconds_per_percent = prediction.seconds as f64 / self.percentage.min(100) as f64;
+        let expected =
+            pattern.time_until(self.percentage, 0, Utc::now(), &Local, seconds_per_percent);
+        match expected {
+            Some(expected) => Ok(Some(prediction.rescaled(expected))),
+            None => Ok(Some(prediction)),
         }
     }
 
-    pub fn fmt_remaining(&self) -> anyhow::Result<Option<String>> {
-        let duration = self.remaining()?;
-        match duration {
-            Some(duration) => Ok(Some(duration.as_human().to_string())),
+    /// How long until the battery is full, at the rate it charged before
+    pub fn time_to_full(&self, db: &Database) -> Result<Option<Duration>> {
+        let entries = BatteryData::get(db, &self.device_id)?;
+        let rate = BatteryData::charge_rate(&entries, &heartbeat::gaps(db)?);
+        let missing = 100 - self.percentage.min(100) as i64;
+        Ok(rate.map(|rate| Duration::seconds(rate * missing)))
+    }
+
+    /// The range the battery is most likely to last, like "1d 20h – 2d 6h"
+    pub fn fmt_remaining(&self, db: &Database) -> Result<Option<String>> {
+        let prediction = self.remaining(db)?;
+        match prediction {
+            Some(prediction) => Ok(Some(prediction.range().as_human().to_string())),
             None => Ok(None),
         }
     }
 }
 
+/// Seconds per percent while discharging, according to the estimator picked in the settings
+pub(crate) fn consumption(db: &Database, device_id: &str) -> Result<Option<i64>> {
+    let entries = BatteryData::get(db, device_id)?;
+    let measurements = BatteryData::measurements(&entries, &heartbeat::gaps(db)?);
+    Ok(estimator::load(db)?.estimate(&measurements))
+}
+
 impl fmt::Display for BatteryStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let icon = if self.charging {
@@ -115,74 +160,140 @@ impl fmt::Display for BatteryStatus {
 
 #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub(crate) struct BatteryData {
+    pub device_id: String,
     pub product_id: u16,
-    pub created_at: String,
+    /// Stored, and sent to the frontend, as milliseconds since the Unix epoch
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub created_at: DateTime<Utc>,
     pub percentage: u8,
     pub charging: bool,
+    /// Time the device spent asleep since the previous sample, for a sample standing in for the
+    /// ones compacted before it
+    #[serde(default)]
+    pub idle_seconds: i64,
+    /// Times the device woke up since the previous sample, for a compacted sample too
+    #[serde(default)]
+    pub wake_ups: u32,
 }
 
 impl BatteryData {
     #[allow(dead_code)]
     pub fn new(product_id: u16, created_at: &str, percentage: u8, charging: bool) -> Self {
         Self {
+            device_id: crate::device::fallback_id(product_id, 0),
             product_id,
-            created_at: created_at.to_string(),
+            created_at: Utc.from_utc_datetime(
+                &NaiveDateTime::parse_from_str(created_at, "%Y-%m-%d %H:%M:%S").unwrap(),
+            ),
             percentage,
             charging,
+            idle_seconds: 0,
+            wake_ups: 0,
         }
     }
 
-    pub fn get(product_id: u16) -> anyhow::Result<Vec<BatteryData>> {
-        let db = database::Conn::new()?.conn;
-        let mut statement = db
-            .prepare("SELECT * FROM battery WHERE product_id = ?1")
-            .unwrap();
-        let res = serde_rusqlite::from_rows::<BatteryData>(statement.query([&product_id]).unwrap());
-        let res = res.collect::<Vec<_>>();
-        let res = res.into_iter().collect::<Result<Vec<_>, _>>();
-        Ok(res?)
+    /// Whether the device was asleep from this sample until the next one
+    ///
+    /// The monitor only writes samples when something changes, so a 0% sample is written as the
+    /// device falls asleep and the next one as it wakes up.
+    pub fn is_asleep(&self) -> bool {
+        !self.charging && self.percentage == 0
     }
 
-    pub fn timestamp(&self) -> NaiveDateTime {
-        NaiveDateTime::parse_from_str(&self.created_at, "%Y-%m-%d %H:%M:%S").unwrap()
+    pub fn get(db: &Database, device_id: &str) -> Result<Vec<BatteryData>> {
+        db.with(|conn| {
+            let mut statement = conn.prepare_cached(
+                "SELECT * FROM battery WHERE device_id = ?1 ORDER BY created_at, id",
+            )?;
+            let res = serde_rusqlite::from_rows::<BatteryData>(statement.query([device_id])?);
+            let res = res.collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok(res)
+        })
+    }
+
+    /// Samples recorded from `since`, in milliseconds since the Unix epoch, on, oldest first
+    pub fn get_since(db: &Database, device_id: &str, since: i64) -> Result<Vec<BatteryData>> {
+        db.with(|conn| {
+            let mut statement = conn.prepare_cached(
+                "SELECT * FROM battery WHERE device_id = ?1 AND created_at >= ?2 ORDER BY created_at, id",
+            )?;
+            let res =
+                serde_rusqlite::from_rows::<BatteryData>(statement.query((device_id, since))?);
+            let res = res.collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok(res)
+        })
     }
 
     /// Calculates the average time it takes for the battery to lose 1% of charge
     ///
     /// - Calculates the time it takes to discharge each 1% of the battery (in seconds)
-    /// - If the current charge percentage is zero, it means that the device is sleeping, so
-    /// accumulate the time between the last charge and the current time
+    /// - If the charge percentage is zero, it means that the device fell asleep, so accumulate
+    ///   the time between it and the next entry, see [`BatteryData::is_asleep`]
     /// - The next time the charge percentage is greater than zero, it means that the device
-    /// woke up, so calculate keep the start date as the last time the percentage was greater than
-    /// zero
+    ///   woke up, so calculate keep the start date as the last time the percentage was greater than
+    ///   zero
     /// - When the charge percentage drop 1% again, calculate the first time for the last
-    /// percentage, taking out the accumulated idle time
-    pub fn consumption(entries: &Vec<BatteryData>) -> Option<i64> {
+    ///   percentage, taking out the accumulated idle time
+    /// - Only discharging counts: entries taken while charging end the current measurement, which
+    ///   starts over once the device is unplugged, and a percentage that went up starts over from
+    ///   there
+    /// - Time razermon wasn't running or the computer was suspended is taken out, the same way
+    ///   idle time is
+    pub fn consumption(entries: &[BatteryData], gaps: &[Gap]) -> Option<i64> {
+        Mean.estimate(&Self::measurements(entries, gaps))
+    }
+
+    /// Every discharge measurement [`BatteryData::consumption`] averages, oldest first
+    pub fn measurements(entries: &[BatteryData], gaps: &[Gap]) -> Vec<Measurement> {
         let mut measurements = vec![];
         let mut idle_intervals = vec![];
         let mut last_entry: Option<&BatteryData> = None;
         let mut last_line_entry: Option<&BatteryData> = None;
         for entry in entries {
-            if entry.percentage > 0 {
+            if let Some(asleep) = last_line_entry.filter(|last| last.is_asleep()) {
+                // gets the duration between current and last entries
+                let idle_duration = entry.created_at
+                    - asleep.created_at
+                    - heartbeat::unobserved(gaps, asleep.created_at, entry.created_at);
+                idle_intervals.push(idle_duration.num_seconds());
+            }
+            if entry.charging {
+                last_entry = None;
+                idle_intervals = vec![];
+            } else if entry.percentage > 0 {
                 if let Some(cur_last_entry) = last_entry {
-                    if entry.percentage != cur_last_entry.percentage {
+                    if entry.percentage > cur_last_entry.percentage {
+                        // charged while nobody was looking, not a measurement either
+                        last_entry = Some(entry);
+                        idle_intervals = vec![];
+                    } else if entry.percentage < cur_last_entry.percentage {
                         // gets the duration between current and last entries, discounting the current idle time
                         // println!(
                         //     "\nCalculating duration between {:?} ({}) and {:?} ({})",
                         //     entry.percentage,
-                        //     entry.timestamp(),
+                        //     entry.created_at,
                         //     cur_last_entry.percentage,
-                        //     cur_last_entry.timestamp(),
+                        //     cur_last_entry.created_at,
                         // );
-                        let duration = entry.timestamp() - cur_last_entry.timestamp();
+                        let duration = entry.created_at - cur_last_entry.created_at;
                         // println!("  - raw duration: {:?}", duration);
-                        let idle_time_seconds = idle_intervals.iter().sum::<i64>();
+                        let idle_time_seconds =
+                            idle_intervals.iter().sum::<i64>() + entry.idle_seconds;
                         // println!("  - idle time: {:?}", idle_time_seconds);
                         let idle_time = chrono::Duration::seconds(idle_time_seconds);
                         // println!("  - idle time (duration): {:?}", idle_time);
-                        let duration = duration - idle_time;
+                        let unobserved = heartbeat::unobserved(
+                            gaps,
+                            cur_last_entry.created_at,
+                            entry.created_at,
+                        );
+                        let duration = duration - idle_time - unobserved;
                         // println!("  - duration: {:?}", duration);
-                        measurements.push(duration);
+                        measurements.push(Measurement {
+                            at: entry.created_at,
+                            percentage: cur_last_entry.percentage,
+                            seconds: duration.num_seconds(),
+                        });
 
                         last_entry = Some(entry);
                         idle_intervals = vec![];
@@ -191,34 +302,50 @@ impl BatteryData {
                     last_entry = Some(entry);
                     idle_intervals = vec![];
                 }
-            } else if let Some(last_entry) = last_line_entry {
-                // gets the duration between current and last entries
-                let idle_duration = entry.timestamp() - last_entry.timestamp();
-                // println!(
-                //     "  [!] adding idle time between {} ({:?}) and {} ({:?}) = {:?}",
-                //     entry.percentage,
-                //     entry.timestamp(),
-                //     last_entry.percentage,
-                //     last_entry.timestamp(),
-                //     idle_duration
-                // );
-                idle_intervals.push(idle_duration.num_seconds());
             }
             last_line_entry = Some(entry);
         }
-        let total_time = measurements.iter().map(|d| d.num_seconds()).sum::<i64>();
-        // TODO: Make this an Option and return None when there are no measurements to assure we're
-        // telling the user it's to early to have an idea on how much the battery will last
-        if measurements.is_empty() {
+        measurements
+    }
+
+    /// Calculates the average time it takes for the battery to gain 1% of charge
+    ///
+    /// Only rising percentages between entries taken while charging count. Unplugging, the device
+    /// going to sleep or the percentage dropping starts over from the next entry, and time
+    /// razermon wasn't running is taken out.
+    pub fn charge_rate(entries: &[BatteryData], gaps: &[Gap]) -> Option<i64> {
+        let mut seconds = 0;
+        let mut gained = 0;
+        let mut last_entry: Option<&BatteryData> = None;
+        for entry in entries {
+            if !entry.charging || entry.percentage == 0 {
+                last_entry = None;
+                continue;
+            }
+            match last_entry {
+                Some(last) if entry.percentage > last.percentage => {
+                    let duration = entry.created_at
+                        - last.created_at
+                        - heartbeat::unobserved(gaps, last.created_at, entry.created_at);
+                    seconds += duration.num_seconds();
+                    gained += (entry.percentage - last.percentage) as i64;
+                    last_entry = Some(entry);
+                }
+                Some(last) if entry.percentage == last.percentage => {}
+                _ => last_entry = Some(entry),
+            }
+        }
+        if gained == 0 {
             return None;
         }
-        Some(total_time / measurements.len() as i64)
+        Some(seconds / gained)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::device::FakeSource;
 
     #[test]
     fn test_battery_duration() {
@@ -233,13 +360,163 @@ mod tests {
             BatteryData::new(1, "2022-01-01 23:51:35", 73, false),
         ];
 
-        let duration = BatteryData::consumption(&entries);
-        assert_eq!(duration, Some(2728));
+        let duration = BatteryData::consumption(&entries, &[]);
+        assert_eq!(duration, Some(2264));
+    }
+
+    #[test]
+    fn test_charging_is_a_boundary() {
+        let entries = vec![
+            BatteryData::new(1, "2022-01-01 20:00:00", 76, false),
+            BatteryData::new(1, "2022-01-01 20:30:00", 75, false),
+            BatteryData::new(1, "2022-01-01 21:00:00", 75, true),
+            BatteryData::new(1, "2022-01-01 21:05:00", 76, true),
+            BatteryData::new(1, "2022-01-01 21:10:00", 77, true),
+            BatteryData::new(1, "2022-01-01 21:15:00", 77, false),
+            BatteryData::new(1, "2022-01-01 22:15:00", 76, false),
+        ];
+
+        let duration = BatteryData::consumption(&entries, &[]);
+        assert_eq!(duration, Some(2700));
+    }
+
+    #[test]
+    fn test_charging_only() {
+        let entries = vec![
+            BatteryData::new(1, "2022-01-01 20:00:00", 74, true),
+            BatteryData::new(1, "2022-01-01 20:05:00", 75, true),
+            BatteryData::new(1, "2022-01-01 20:10:00", 76, true),
+        ];
+
+        assert_eq!(BatteryData::consumption(&entries, &[]), None);
+    }
+
+    #[test]
+    fn test_rising_percentage_is_ignored() {
+        // charged while the app wasn't running
+        let entries = vec![
+            BatteryData::new(1, "2022-01-01 20:00:00", 74, false),
+            BatteryData::new(1, "2022-01-01 20:40:00", 73, false),
+            BatteryData::new(1, "2022-01-02 09:00:00", 95, false),
+            BatteryData::new(1, "2022-01-02 09:20:00", 94, false),
+        ];
+
+        let duration = BatteryData::consumption(&entries, &[]);
+        assert_eq!(duration, Some(1800));
+    }
+
+    #[test]
+    fn test_unobserved_gaps() {
+        let entries = vec![
+            BatteryData::new(1, "2022-01-01 20:00:00", 76, false),
+            BatteryData::new(1, "2022-01-01 20:30:00", 75, false),
+            BatteryData::new(1, "2022-01-02 09:00:00", 74, false),
+            BatteryData::new(1, "2022-01-02 09:10:00", 0, false),
+            BatteryData::new(1, "2022-01-02 09:40:00", 73, false),
+        ];
+        // closed overnight, then suspended while the device was asleep
+        let gaps = [
+            Gap {
+                start: BatteryData::new(1, "2022-01-01 20:45:00", 0, false).created_at,
+                end: BatteryData::new(1, "2022-01-02 08:45:00", 0, false).created_at,
+            },
+            Gap {
+                start: BatteryData::new(1, "2022-01-02 09:15:00", 0, false).created_at,
+                end: BatteryData::new(1, "2022-01-02 09:18:00", 0, false).created_at,
+            },
+        ];
+
+        assert_eq!(BatteryData::consumption(&entries, &[]), Some(15800));
+        // 1800s, then 45000s - 43200s unobserved, then 2400s - 1620s idle - 180s unobserved
+        assert_eq!(BatteryData::consumption(&entries, &gaps), Some(1400));
+    }
+
+    #[test]
+    fn test_charge_rate() {
+        let entries = vec![
+            BatteryData::new(1, "2022-01-01 20:00:00", 40, false),
+            BatteryData::new(1, "2022-01-01 20:10:00", 40, true),
+            BatteryData::new(1, "2022-01-01 20:13:00", 41, true),
+            BatteryData::new(1, "2022-01-01 20:19:00", 43, true),
+            BatteryData::new(1, "2022-01-01 20:20:00", 43, false),
+            // unplugged for a while, the percentage the device came back with doesn't count
+            BatteryData::new(1, "2022-01-01 23:00:00", 30, true),
+            BatteryData::new(1, "2022-01-01 23:06:00", 32, true),
+        ];
+
+        assert_eq!(BatteryData::charge_rate(&entries, &[]), Some(180));
+        assert_eq!(BatteryData::charge_rate(&entries[..2], &[]), None);
+    }
+
+    #[test]
+    fn test_status_from_source() {
+        let source = FakeSource::new();
+        let id = source.connect(1, Some("M1"), "Mouse", 42, true);
+
+        let status = BatteryStatus::get(&source, &id).unwrap().unwrap();
+        assert_eq!(status.device_id, "M1");
+        assert_eq!(status.name, "Mouse");
+        assert_eq!(status.percentage, 42);
+        assert!(status.charging);
+
+        assert!(BatteryStatus::get(&source, "M2").unwrap().is_none());
+
+        let devices = source.devices().unwrap();
+        source.disconnect(&id);
+        assert!(BatteryStatus::get_from_devices(&source, &devices, &id).is_err());
+    }
+
+    #[test]
+    fn test_last_status() {
+        let db = Database::open_in_memory().unwrap();
+        assert_eq!(BatteryStatus::last_status(&db, "M1").unwrap(), None);
+
+        let mut status = BatteryStatus {
+            device_id: "M1".to_string(),
+            product_id: 1,
+            name: "Mouse".to_string(),
+            percentage: 80,
+            charging: false,
+        };
+        status.save(&db).unwrap();
+        // asleep
+        status.percentage = 0;
+        status.save(&db).unwrap();
+        assert_eq!(BatteryStatus::last_status(&db, "M1").unwrap(), Some(80));
+        assert_eq!(BatteryStatus::last_status(&db, "M2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_save_timestamps() {
+        let db = Database::open_in_memory().unwrap();
+        let status = BatteryStatus {
+            device_id: "M1".to_string(),
+            product_id: 1,
+            name: "Mouse".to_string(),
+            percentage: 80,
+            charging: false,
+        };
+        let created_at = Utc.timestamp_millis_opt(1666728000123).unwrap();
+        status.save_at(&db, created_at).unwrap();
+
+        let stored: i64 = db
+            .with(
+                |conn| Ok(conn.query_row("SELECT created_at FROM battery", (), |row| row.get(0))?),
+            )
+            .unwrap();
+        assert_eq!(stored, 1666728000123);
+    }
+
+    #[test]
+    fn test_serialize_created_at() {
+        let entry = BatteryData::new(1, "2022-10-25 20:00:00", 80, false);
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["created_at"], 1666728000000i64);
     }
 
     #[test]
     fn test_no_measurements() {
-        let duration = BatteryData::consumption(&vec![]);
+        let duration = BatteryData::consumption(&[], &[]);
         assert_eq!(duration, None);
     }
 }
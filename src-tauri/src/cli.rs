@@ -0,0 +1,102 @@
+use crate::database::{Database, DB_FLAG};
+use crate::health;
+
+const USAGE: &str = "usage: razermon [--db <path>] [health [<device id>]]";
+
+/// Runs the command given on the command line, like `razermon health`, returning the exit code,
+/// or `None` when there's no command and the app should start
+pub(crate) fn run(db: &Database, args: impl IntoIterator<Item = String>) -> Option<i32> {
+    let args = positional(args);
+    let (command, args) = args.split_first()?;
+    let code = match command.as_str() {
+        "health" => health(db, args),
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    };
+    Some(code)
+}
+
+/// Prints the health report of a device, or of every device with battery history
+fn health(db: &Database, args: &[String]) -> i32 {
+    let device_ids = match args {
+        [] => health::device_ids(db),
+        [device_id] => Ok(vec![device_id.clone()]),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    let reports = device_ids.and_then(|ids| {
+        ids.iter()
+            .map(|id| health::report(db, id))
+            .collect::<crate::error::Result<Vec<_>>>()
+    });
+    match reports {
+        Ok(reports) if reports.is_empty() => {
+            println!("No battery history yet");
+            0
+        }
+        Ok(reports) => {
+            let degraded = reports.iter().any(|r| r.degraded);
+            for report in reports {
+                print!("{}", report);
+            }
+            // lets scripts tell a battery needs replacing
+            if degraded {
+                1
+            } else {
+                0
+            }
+        }
+        Err(err) => {
+            eprintln!("Error building the health report: {}", err);
+            2
+        }
+    }
+}
+
+/// Arguments that aren't flags, leaving out the database location and whatever the OS passes
+/// along, like `-psn_0_12345` on macOS
+fn positional(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut positional = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == DB_FLAG {
+            args.next();
+        } else if !arg.starts_with('-') {
+            positional.push(arg);
+        }
+    }
+    positional
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_positional() {
+        assert!(positional(args(&[])).is_empty());
+        assert!(positional(args(&["--db", "/tmp/a.db", "-psn_0_1"])).is_empty());
+        assert_eq!(
+            positional(args(&["--db=:memory:", "health", "M1"])),
+            args(&["health", "M1"])
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let db = Database::open_in_memory().unwrap();
+        assert_eq!(run(&db, args(&["--db", ":memory:"])), None);
+        assert_eq!(run(&db, args(&["health"])), Some(0));
+        assert_eq!(run(&db, args(&["health", "M1"])), Some(0));
+        assert_eq!(run(&db, args(&["health", "M1", "M2"])), Some(2));
+        assert_eq!(run(&db, args(&["unknown"])), Some(2));
+    }
+}
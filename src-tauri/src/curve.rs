@@ -0,0 +1,243 @@
+use std::ops::Range;
+
+use chrono::Duration;
+
+use crate::battery::BatteryData;
+use crate::database::Database;
+use crate::error::Result;
+use crate::estimator::{self, Estimator, Measurement};
+use crate::heartbeat;
+
+/// How many percentage points share a bucket of the curve
+const BUCKET_SIZE: u8 = 10;
+
+/// Buckets learned from fewer measurements than this use the estimate of the whole history
+/// instead, so a single odd measurement doesn't make up a whole range
+const MIN_BUCKET_MEASUREMENTS: usize = 3;
+
+/// How many standard deviations away from the expected value the 10th and 90th percentiles of a
+/// normal distribution are
+const P10_P90_DEVIATIONS: f64 = 1.2816;
+
+/// How long a device's battery takes to lose 1% at each level
+///
+/// Batteries don't drain linearly: the first and the last few percents usually go by faster, or
+/// slower, than the middle ones. The levels are grouped in buckets of ten, from 1-10% to 91-100%.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct DischargeCurve {
+    buckets: Vec<Option<i64>>,
+    /// Estimate over every level, for the buckets without enough measurements
+    overall: Option<i64>,
+    /// Standard deviation of the measurements around the curve, in seconds
+    deviation: f64,
+    /// Measurements the curve was learned from
+    samples: usize,
+}
+
+/// How long the battery is expected to take to drain, with the range it most likely falls in
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct Prediction {
+    pub seconds: i64,
+    /// 10th percentile
+    pub low_seconds: i64,
+    /// 90th percentile
+    pub high_seconds: i64,
+    /// Measurements the prediction is based on
+    pub samples: usize,
+}
+
+impl Prediction {
+    pub fn expected(&self) -> Duration {
+        Duration::seconds(self.seconds)
+    }
+
+    /// The same prediction centered on another expected time, with the range scaled to match
+    pub fn rescaled(&self, expected: Duration) -> Self {
+        let seconds = expected.num_seconds();
+        let scale = |bound: i64| match self.seconds {
+            0 => seconds,
+            _ => (bound as f64 * seconds as f64 / self.seconds as f64).round() as i64,
+        };
+        Self {
+            seconds,
+            low_seconds: scale(self.low_seconds),
+            high_seconds: scale(self.high_seconds),
+            samples: self.samples,
+        }
+    }
+
+    pub fn range(&self) -> Range<Duration> {
+        Duration::seconds(self.low_seconds)..Duration::seconds(self.high_seconds)
+    }
+}
+
+impl DischargeCurve {
+    /// Learns the curve from discharge measurements, estimating each bucket with `estimator`
+    pub fn learn(measurements: &[Measurement], estimator: &dyn Estimator) -> Self {
+        let mut grouped = vec![vec![]; bucket(100) + 1];
+        for measurement in measurements.iter().filter(|m| m.percentage > 0) {
+            grouped[bucket(measurement.percentage)].push(*measurement);
+        }
+        let buckets = grouped
+            .iter()
+            .map(|measurements| {
+                if measurements.len() < MIN_BUCKET_MEASUREMENTS {
+                    return None;
+                }
+                estimator.estimate(measurements)
+            })
+            .collect();
+        let mut curve = Self {
+            buckets,
+            overall: estimator.estimate(measurements),
+            deviation: 0.0,
+            samples: 0,
+        };
+
+        let residuals = measurements
+            .iter()
+            .filter(|m| m.percentage > 0)
+            .filter_map(|m| Some((m.seconds - curve.seconds_at(m.percentage)?) as f64))
+            .collect::<Vec<_>>();
+        curve.samples = residuals.len();
+        if !residuals.is_empty() {
+            let variance = residuals.iter().map(|r| r * r).sum::<f64>() / residuals.len() as f64;
+            curve.deviation = variance.sqrt();
+        }
+        curve
+    }
+
+    /// Expected seconds to lose 1% at `percentage`
+    pub fn seconds_at(&self, percentage: u8) -> Option<i64> {
+        let percentage = percentage.clamp(1, 100);
+        self.buckets[bucket(percentage)].or(self.overall)
+    }
+
+    /// Expected time to drain from `from` down to `to` percent, adding up every level in between
+    pub fn time_between(&self, from: u8, to: u8) -> Option<Duration> {
+        let from = from.min(100);
+        if from <= to {
+            return Some(Duration::zero());
+        }
+        let seconds = (to + 1..=from)
+            .map(|percentage| self.seconds_at(percentage))
+            .sum::<Option<i64>>()?;
+        Some(Duration::seconds(seconds))
+    }
+
+    /// Same as [`DischargeCurve::time_between`], along with the range from the 10th to the 90th
+    /// percentile
+    ///
+    /// Each percent is taken as an independent draw around the curve, so the spread grows with
+    /// the square root of the number of percents to drain rather than with the number itself.
+    pub fn predict(&self, from: u8, to: u8) -> Option<Prediction> {
+        let expected = self.time_between(from, to)?.num_seconds();
+        let percents = from.min(100).saturating_sub(to) as f64;
+        let spread = (P10_P90_DEVIATIONS * self.deviation * percents.sqrt()).round() as i64;
+        Some(Prediction {
+            seconds: expected,
+            low_seconds: (expected - spread).max(0),
+            high_seconds: expected + spread,
+            samples: self.samples,
+        })
+    }
+}
+
+fn bucket(percentage: u8) -> usize {
+    ((percentage.max(1) - 1) / BUCKET_SIZE) as usize
+}
+
+/// The curve of a device, learned from its whole history with the estimator picked in the
+/// settings
+pub(crate) fn load(db: &Database, device_id: &str) -> Result<DischargeCurve> {
+    let entries = BatteryData::get(db, device_id)?;
+    let measurements = BatteryData::measurements(&entries, &heartbeat::gaps(db)?);
+    Ok(DischargeCurve::learn(
+        &measurements,
+        estimator::load(db)?.as_ref(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::estimator::Mean;
+
+    fn measurement(percentage: u8, seconds: i64) -> Measurement {
+        Measurement {
+            at: BatteryData::new(1, "2022-01-01 20:00:00", 0, false).created_at,
+            percentage,
+            seconds,
+        }
+    }
+
+    #[test]
+    fn test_curve() {
+        let mut measurements = vec![];
+        // the last 10% go by fast
+        for percentage in [10, 8, 5] {
+            measurements.push(measurement(percentage, 600));
+        }
+        for percentage in [60, 55, 52, 51] {
+            measurements.push(measurement(percentage, 3600));
+        }
+        // not enough to learn the top bucket
+        measurements.push(measurement(95, 600));
+
+        let curve = DischargeCurve::learn(&measurements, &Mean);
+        assert_eq!(curve.seconds_at(1), Some(600));
+        assert_eq!(curve.seconds_at(10), Some(600));
+        assert_eq!(curve.seconds_at(51), Some(3600));
+        // (3 * 600 + 4 * 3600 + 600) / 8
+        assert_eq!(curve.seconds_at(95), Some(2100));
+        assert_eq!(curve.seconds_at(0), Some(600));
+
+        assert_eq!(
+            curve.time_between(12, 0),
+            Some(Duration::seconds(10 * 600 + 2 * 2100))
+        );
+        assert_eq!(
+            curve.time_between(60, 50),
+            Some(Duration::seconds(10 * 3600))
+        );
+        assert_eq!(curve.time_between(10, 20), Some(Duration::zero()));
+    }
+
+    #[test]
+    fn test_empty_curve() {
+        let curve = DischargeCurve::learn(&[], &Mean);
+        assert_eq!(curve.seconds_at(50), None);
+        assert_eq!(curve.time_between(50, 0), None);
+        assert_eq!(curve.time_between(0, 0), Some(Duration::zero()));
+    }
+
+    #[test]
+    fn test_prediction() {
+        let mut measurements = vec![];
+        for seconds in [3000, 4200, 3000, 4200] {
+            measurements.push(measurement(50, seconds));
+        }
+        let curve = DischargeCurve::learn(&measurements, &Mean);
+
+        // 600s away from the curve every time, over 4 percents
+        let prediction = curve.predict(54, 50).unwrap();
+        assert_eq!(
+            prediction,
+            Prediction {
+                seconds: 4 * 3600,
+                low_seconds: 4 * 3600 - 1538,
+                high_seconds: 4 * 3600 + 1538,
+                samples: 4,
+            }
+        );
+        assert_eq!(
+            curve.predict(50, 50).unwrap().range(),
+            Duration::zero()..Duration::zero()
+        );
+        assert_eq!(
+            prediction.rescaled(Duration::hours(8)).range(),
+            Duration::seconds(8 * 3600 - 3076)..Duration::seconds(8 * 3600 + 3076)
+        );
+        assert_eq!(DischargeCurve::learn(&[], &Mean).predict(50, 0), None);
+    }
+}
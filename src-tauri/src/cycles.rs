@@ -0,0 +1,178 @@
+use rusqlite::OptionalExtension;
+
+use crate::battery::BatteryData;
+use crate::database::Database;
+use crate::error::Result;
+
+/// How much a device has been used, counted from its battery history
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub(crate) struct ChargeCycles {
+    /// Times the device was put on the charger
+    pub charge_events: i64,
+    /// Percents lost while discharging, over the whole history
+    pub discharged_percent: i64,
+    /// Equivalent full cycles, a cycle being 100% discharged, in one go or not
+    pub full_cycles: f64,
+}
+
+/// Counts kept in the `charge_cycles` table, along with where counting left off
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Counter {
+    charge_events: i64,
+    discharged_percent: i64,
+    /// Last sample counted, in milliseconds since the Unix epoch
+    counted_until: Option<i64>,
+    /// Last percentage seen while the device was awake
+    last_percentage: Option<u8>,
+    last_charging: Option<bool>,
+}
+
+impl Counter {
+    /// Adds up samples, oldest first, recorded after the ones already counted
+    fn count(&mut self, samples: &[BatteryData]) {
+        for sample in samples {
+            if sample.charging && self.last_charging != Some(true) {
+                self.charge_events += 1;
+            }
+            if sample.percentage > 0 {
+                match self.last_percentage {
+                    Some(last) if !sample.charging && sample.percentage < last => {
+                        self.discharged_percent += (last - sample.percentage) as i64;
+                    }
+                    _ => {}
+                }
+                self.last_percentage = Some(sample.percentage);
+            }
+            self.last_charging = Some(sample.charging);
+            self.counted_until = Some(sample.created_at.timestamp_millis());
+        }
+    }
+
+    fn cycles(&self) -> ChargeCycles {
+        ChargeCycles {
+            charge_events: self.charge_events,
+            discharged_percent: self.discharged_percent,
+            full_cycles: self.discharged_percent as f64 / 100.0,
+        }
+    }
+
+    fn load(db: &Database, device_id: &str) -> Result<Self> {
+        db.with(|conn| {
+            let counter = conn
+                .prepare_cached(
+                    "SELECT charge_events, discharged_percent, counted_until, last_percentage, last_charging FROM charge_cycles WHERE device_id = ?1",
+                )?
+                .query_row([device_id], |row| {
+                    Ok(Counter {
+                        charge_events: row.get(0)?,
+                        discharged_percent: row.get(1)?,
+                        counted_until: Some(row.get(2)?),
+                        last_percentage: row.get(3)?,
+                        last_charging: row.get(4)?,
+                    })
+                })
+                .optional()?;
+            Ok(counter.unwrap_or_default())
+        })
+    }
+
+    fn save(&self, db: &Database, device_id: &str) -> Result<()> {
+        let Some(counted_until) = self.counted_until else {
+            return Ok(());
+        };
+        db.with(|conn| {
+            conn.prepare_cached(
+                "INSERT OR REPLACE INTO charge_cycles (device_id, charge_events, discharged_percent, counted_until, last_percentage, last_charging)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?
+            .execute((
+                device_id,
+                self.charge_events,
+                self.discharged_percent,
+                counted_until,
+                self.last_percentage,
+                self.last_charging,
+            ))?;
+            Ok(())
+        })
+    }
+}
+
+/// Counts the samples of a device recorded since the last count, returning the totals
+///
+/// Only transitions matter, so counts come out the same after old samples are compacted.
+pub(crate) fn count(db: &Database, device_id: &str) -> Result<ChargeCycles> {
+    let mut counter = Counter::load(db, device_id)?;
+    let samples = match counter.counted_until {
+        Some(millis) => BatteryData::get_since(db, device_id, millis + 1)?,
+        None => BatteryData::get(db, device_id)?,
+    };
+    if !samples.is_empty() {
+        counter.count(&samples);
+        counter.save(db, device_id)?;
+    }
+    Ok(counter.cycles())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::BatteryStatus;
+
+    fn samples() -> Vec<BatteryData> {
+        vec![
+            BatteryData::new(1, "2022-01-01 10:00:00", 80, false),
+            BatteryData::new(1, "2022-01-01 11:00:00", 60, false),
+            // asleep
+            BatteryData::new(1, "2022-01-01 12:00:00", 0, false),
+            BatteryData::new(1, "2022-01-01 13:00:00", 55, false),
+            BatteryData::new(1, "2022-01-01 14:00:00", 55, true),
+            BatteryData::new(1, "2022-01-01 15:00:00", 100, true),
+            BatteryData::new(1, "2022-01-01 16:00:00", 90, false),
+            BatteryData::new(1, "2022-01-01 17:00:00", 90, true),
+            BatteryData::new(1, "2022-01-01 18:00:00", 95, true),
+        ]
+    }
+
+    #[test]
+    fn test_count() {
+        let mut counter = Counter::default();
+        counter.count(&samples());
+        assert_eq!(
+            counter.cycles(),
+            ChargeCycles {
+                charge_events: 2,
+                discharged_percent: 35,
+                full_cycles: 0.35,
+            }
+        );
+    }
+
+    #[test]
+    fn test_count_incrementally() {
+        let db = Database::open_in_memory().unwrap();
+        assert_eq!(count(&db, "0001:0").unwrap(), ChargeCycles::default());
+
+        let samples = samples();
+        for (index, sample) in samples.iter().enumerate() {
+            let status = BatteryStatus {
+                device_id: sample.device_id.clone(),
+                product_id: sample.product_id,
+                name: "Mouse".to_string(),
+                percentage: sample.percentage,
+                charging: sample.charging,
+            };
+            status.save_at(&db, sample.created_at).unwrap();
+            // counting in the middle of a run doesn't count anything twice
+            if index % 3 == 0 {
+                count(&db, "0001:0").unwrap();
+            }
+        }
+
+        let mut counter = Counter::default();
+        counter.count(&samples);
+        assert_eq!(count(&db, "0001:0").unwrap(), counter.cycles());
+        assert_eq!(Counter::load(&db, "0001:0").unwrap(), counter);
+        assert_eq!(count(&db, "M2").unwrap(), ChargeCycles::default());
+    }
+}
@@ -1,25 +1,494 @@
-use std::fs;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use rusqlite::Connection;
+use rusqlite::{Connection, Transaction};
 
-#[derive(Debug)]
-pub struct Conn {
-    pub conn: Connection,
+use crate::error::{RazermonError, Result};
+
+/// How many prepared statements each connection keeps around for reuse
+const STATEMENT_CACHE_CAPACITY: usize = 32;
+
+/// Environment variable overriding where the database lives
+const DB_ENV: &str = "RAZERMON_DB";
+
+/// Command-line flag overriding where the database lives, as `--db <path>` or `--db=<path>`
+pub(crate) const DB_FLAG: &str = "--db";
+
+/// Location value for a database that only lives as long as the app
+const MEMORY: &str = ":memory:";
+
+/// Where the database lives
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Location {
+    File(PathBuf),
+    /// Nothing is written to disk, for tests, CI runners and trying things out
+    Memory,
+}
+
+/// `config.json` in the razermon config directory, for settings needed before the database is
+/// open
+#[derive(Debug, Default, serde::Deserialize)]
+struct Config {
+    database: Option<String>,
+}
+
+impl Location {
+    /// Picks the database location, taking the first of:
+    ///
+    /// - the `--db` command-line flag
+    /// - the `RAZERMON_DB` environment variable
+    /// - the `database` key of `config.json` in the razermon config directory, relative to it
+    /// - `razermon.db` in the razermon config directory
+    ///
+    /// Any of them can be `:memory:` to keep everything in memory.
+    pub fn resolve() -> Result<Self> {
+        Self::resolve_from(
+            env::args().skip(1),
+            env::var(DB_ENV).ok(),
+            dirs::config_dir().map(|dir| dir.join("razermon")),
+        )
+    }
+
+    fn resolve_from(
+        args: impl IntoIterator<Item = String>,
+        env: Option<String>,
+        config_dir: Option<PathBuf>,
+    ) -> Result<Self> {
+        if let Some(value) = flag_value(args) {
+            return Ok(Self::parse(&value, None));
+        }
+        if let Some(value) = env.filter(|v| !v.is_empty()) {
+            return Ok(Self::parse(&value, None));
+        }
+
+        let Some(config_dir) = config_dir else {
+            return Err(RazermonError::Storage(format!(
+                "couldn't find the config directory, set {} or pass {} to choose where to keep the database",
+                DB_ENV, DB_FLAG
+            )));
+        };
+        let config = config_dir.join("config.json");
+        if config.exists() {
+            let contents = fs::read_to_string(&config)?;
+            let config: Config = serde_json::from_str(&contents)
+                .map_err(|err| RazermonError::Parse(format!("{}: {}", config.display(), err)))?;
+            if let Some(value) = config.database {
+                return Ok(Self::parse(&value, Some(&config_dir)));
+            }
+        }
+        Ok(Self::File(config_dir.join("razermon.db")))
+    }
+
+    fn parse(value: &str, base: Option<&Path>) -> Self {
+        if value == MEMORY {
+            return Self::Memory;
+        }
+        match base {
+            Some(base) => Self::File(base.join(value)),
+            None => Self::File(PathBuf::from(value)),
+        }
+    }
+}
+
+fn flag_value(args: impl IntoIterator<Item = String>) -> Option<String> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == DB_FLAG {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(DB_FLAG).and_then(|v| v.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
 }
 
-impl Conn {
-    fn create_database() -> anyhow::Result<Connection> {
-        let db_path = dirs::config_dir().unwrap().join("razermon");
-        fs::create_dir_all(&db_path)?;
-        let db_file = db_path.join("razermon.db");
-        let conn = Connection::open(db_file)?;
-        conn.execute("CREATE TABLE IF NOT EXISTS battery (id INTEGER PRIMARY KEY, created_at TEXT DEFAULT CURRENT_TIMESTAMP, product_id INTEGER, percentage INTEGER, charging INTEGER)", ())?;
-        conn.execute("CREATE TABLE IF NOT EXISTS settings (id INTEGER PRIMARY KEY, created_at TEXT DEFAULT CURRENT_TIMESTAMP, key TEXT UNIQUE, value TEXT)", ())?;
-        Ok(conn)
+/// Shared handle to the SQLite database, held in Tauri managed state
+///
+/// The file is opened and migrated once at startup. Every query after that goes through the same
+/// connection, reusing its cached prepared statements.
+#[derive(Clone)]
+pub(crate) struct Database {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+    pub fn open(location: &Location) -> Result<Self> {
+        match location {
+            Location::File(path) => Self::open_path(path),
+            Location::Memory => Self::open_in_memory(),
+        }
+    }
+
+    pub fn open_path(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Self::setup(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::setup(Connection::open_in_memory()?)
     }
 
-    pub fn new() -> anyhow::Result<Self> {
-        let conn = Self::create_database()?;
-        Ok(Self { conn })
+    fn setup(mut conn: Connection) -> Result<Self> {
+        // lets other processes, like `razermon health`, read while the app writes. Within the
+        // app every query still waits its turn on this one connection.
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` with exclusive access to the connection
+    pub fn with<T>(&self, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|_| RazermonError::Storage("the database lock was poisoned".to_string()))?;
+        f(&mut conn)
+    }
+}
+
+/// A schema change, applied once in its own transaction
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// Every schema change in the order they were introduced. The database's `user_version` is the
+/// number of migrations already applied, so new ones must only ever be appended.
+const MIGRATIONS: &[Migration] = &[
+    create_tables,
+    add_device_ids,
+    timestamps_to_millis,
+    create_sessions,
+    create_observations,
+    create_charge_cycles,
+    create_events,
+    add_idle_seconds,
+    add_wake_ups,
+];
+
+/// Brings the database up to the current schema
+pub(crate) fn migrate(conn: &mut Connection) -> Result<()> {
+    run_migrations(conn, MIGRATIONS)
+}
+
+fn run_migrations(conn: &mut Connection, migrations: &[Migration]) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", (), |row| row.get(0))?;
+    if version > migrations.len() {
+        return Err(RazermonError::NewerSchema(version));
+    }
+
+    for (index, migration) in migrations.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// The tables from before the schema was versioned, which may already exist
+fn create_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS battery (id INTEGER PRIMARY KEY, created_at TEXT DEFAULT CURRENT_TIMESTAMP, product_id INTEGER, percentage INTEGER, charging INTEGER);
+        CREATE TABLE IF NOT EXISTS settings (id INTEGER PRIMARY KEY, created_at TEXT DEFAULT CURRENT_TIMESTAMP, key TEXT UNIQUE, value TEXT);",
+    )
+}
+
+/// Moves rows recorded before devices had their own id, when everything was keyed on the
+/// product id, over to the id of the first device with that product id
+fn add_device_ids(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute("CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, created_at TEXT DEFAULT CURRENT_TIMESTAMP, product_id INTEGER, serial TEXT, name TEXT)", ())?;
+
+    // unversioned databases may already have the column
+    let has_device_id: bool = tx.query_row(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info('battery') WHERE name = 'device_id')",
+        (),
+        |row| row.get(0),
+    )?;
+    if !has_device_id {
+        tx.execute_batch(
+            "ALTER TABLE battery ADD COLUMN device_id TEXT;
+            UPDATE battery SET device_id = printf('%04x:0', product_id);
+            INSERT OR IGNORE INTO devices (id, product_id) SELECT DISTINCT device_id, product_id FROM battery;
+            INSERT OR IGNORE INTO settings (key, value) SELECT 'device_id', printf('%04x:0', value) FROM settings WHERE key = 'product_id';",
+        )?;
+    }
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS battery_device_id ON battery (device_id, created_at)",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Replaces the `CURRENT_TIMESTAMP` text in `battery.created_at`, which is UTC without saying so,
+/// with milliseconds since the Unix epoch. Rows whose timestamp can't be parsed are dropped.
+fn timestamps_to_millis(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE battery_new (id INTEGER PRIMARY KEY, created_at INTEGER NOT NULL, product_id INTEGER, percentage INTEGER, charging INTEGER, device_id TEXT);
+        INSERT INTO battery_new (id, created_at, product_id, percentage, charging, device_id)
+            SELECT id, CAST(ROUND((julianday(created_at) - 2440587.5) * 86400000) AS INTEGER), product_id, percentage, charging, device_id
+            FROM battery WHERE julianday(created_at) IS NOT NULL;
+        DROP TABLE battery;
+        ALTER TABLE battery_new RENAME TO battery;
+        CREATE INDEX battery_device_id ON battery (device_id, created_at);",
+    )
+}
+
+/// Discharge and charge sessions, see `sessions::Session`
+fn create_sessions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE sessions (id INTEGER PRIMARY KEY, device_id TEXT NOT NULL, charging INTEGER NOT NULL, started_at INTEGER NOT NULL, ended_at INTEGER NOT NULL, start_percentage INTEGER, end_percentage INTEGER, idle_seconds INTEGER, seconds_per_percent INTEGER, ended_on_charger INTEGER, UNIQUE (device_id, started_at))",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Periods razermon was running, see `heartbeat::Heartbeat`
+fn create_observations(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE observations (id INTEGER PRIMARY KEY, started_at INTEGER NOT NULL, ended_at INTEGER NOT NULL, reason TEXT NOT NULL)",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Running charge cycle counts, see `cycles::ChargeCycles`
+fn create_charge_cycles(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE charge_cycles (device_id TEXT PRIMARY KEY, charge_events INTEGER NOT NULL, discharged_percent INTEGER NOT NULL, counted_until INTEGER NOT NULL, last_percentage INTEGER, last_charging INTEGER)",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Things worth telling about a device, like `anomaly::Anomaly`
+fn create_events(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE events (id INTEGER PRIMARY KEY, device_id TEXT NOT NULL, created_at INTEGER NOT NULL, kind TEXT NOT NULL, factor REAL, message TEXT NOT NULL);
+        CREATE INDEX events_device_id ON events (device_id, kind, created_at);",
+    )
+}
+
+/// Idle time folded into a sample when the samples before it are compacted
+fn add_idle_seconds(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "ALTER TABLE battery ADD COLUMN idle_seconds INTEGER NOT NULL DEFAULT 0",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Wake-ups folded into a sample along with the idle time
+fn add_wake_ups(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "ALTER TABLE battery ADD COLUMN wake_ups INTEGER NOT NULL DEFAULT 0",
+        (),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The layout every release wrote before devices had their own id
+    const PRODUCT_ID_LAYOUT: &str = "
+        CREATE TABLE battery (id INTEGER PRIMARY KEY, created_at TEXT DEFAULT CURRENT_TIMESTAMP, product_id INTEGER, percentage INTEGER, charging INTEGER);
+        CREATE TABLE settings (id INTEGER PRIMARY KEY, created_at TEXT DEFAULT CURRENT_TIMESTAMP, key TEXT UNIQUE, value TEXT);
+        INSERT INTO battery (created_at, product_id, percentage, charging) VALUES ('2022-10-25 20:00:00', 122, 80, 0);
+        INSERT INTO battery (created_at, product_id, percentage, charging) VALUES ('2022-10-25 21:00:00', 122, 79, 0);
+        INSERT INTO battery (created_at, product_id, percentage, charging) VALUES ('not a date', 122, 78, 0);
+        INSERT INTO settings (key, value) VALUES ('product_id', '122');
+    ";
+
+    /// Device ids added in place, before the schema was versioned
+    const DEVICE_ID_LAYOUT: &str = "
+        CREATE TABLE battery (id INTEGER PRIMARY KEY, created_at TEXT DEFAULT CURRENT_TIMESTAMP, product_id INTEGER, percentage INTEGER, charging INTEGER, device_id TEXT);
+        CREATE TABLE settings (id INTEGER PRIMARY KEY, created_at TEXT DEFAULT CURRENT_TIMESTAMP, key TEXT UNIQUE, value TEXT);
+        CREATE TABLE devices (id TEXT PRIMARY KEY, created_at TEXT DEFAULT CURRENT_TIMESTAMP, product_id INTEGER, serial TEXT, name TEXT);
+        INSERT INTO battery (created_at, product_id, percentage, charging, device_id) VALUES ('2022-10-25 20:00:00', 122, 80, 0, 'PM2012H0001');
+        INSERT INTO devices (id, product_id, serial, name) VALUES ('PM2012H0001', 122, 'PM2012H0001', 'Razer Viper Ultimate');
+        INSERT INTO settings (key, value) VALUES ('device_id', 'PM2012H0001');
+    ";
+
+    fn fixture(layout: &str) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(layout).unwrap();
+        conn
+    }
+
+    fn user_version(conn: &Connection) -> usize {
+        conn.query_row("PRAGMA user_version", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    fn column<T: rusqlite::types::FromSql>(conn: &Connection, name: &str) -> Vec<T> {
+        let mut stmt = conn
+            .prepare(&format!("SELECT {} FROM battery ORDER BY created_at", name))
+            .unwrap();
+        let rows = stmt.query_map((), |row| row.get(0)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    fn device_ids(conn: &Connection) -> Vec<String> {
+        column(conn, "device_id")
+    }
+
+    fn setting(conn: &Connection, key: &str) -> String {
+        conn.query_row("SELECT value FROM settings WHERE key = ?1", [key], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_resolve_location() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let resolve = |a: &[&str], env: Option<&str>| {
+            Location::resolve_from(
+                args(a),
+                env.map(String::from),
+                Some(config_dir.path().to_path_buf()),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            resolve(&[], None),
+            Location::File(config_dir.path().join("razermon.db"))
+        );
+        assert_eq!(
+            resolve(&["--db", "/tmp/a.db"], Some("/tmp/b.db")),
+            Location::File("/tmp/a.db".into())
+        );
+        assert_eq!(resolve(&["--db=:memory:"], None), Location::Memory);
+        assert_eq!(
+            resolve(&[], Some("/tmp/b.db")),
+            Location::File("/tmp/b.db".into())
+        );
+
+        fs::write(
+            config_dir.path().join("config.json"),
+            r#"{ "database": "portable.db" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            resolve(&[], None),
+            Location::File(config_dir.path().join("portable.db"))
+        );
+        assert_eq!(resolve(&[], Some(":memory:")), Location::Memory);
+
+        fs::write(config_dir.path().join("config.json"), "{").unwrap();
+        assert!(Location::resolve_from(args(&[]), None, Some(config_dir.path().into())).is_err());
+    }
+
+    #[test]
+    fn test_resolve_without_config_dir() {
+        assert!(Location::resolve_from(args(&[]), None, None).is_err());
+        assert_eq!(
+            Location::resolve_from(args(&[]), Some(":memory:".to_string()), None).unwrap(),
+            Location::Memory
+        );
+    }
+
+    #[test]
+    fn test_open_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("razermon.db");
+        let db = Database::open(&Location::File(path.clone())).unwrap();
+        crate::settings::set(&db, "device_id", "M1").unwrap();
+        drop(db);
+
+        let db = Database::open(&Location::File(path)).unwrap();
+        assert_eq!(
+            crate::settings::get(&db, "device_id").unwrap(),
+            Some("M1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_migrate_new_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+        assert!(device_ids(&conn).is_empty());
+
+        // already up to date
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_migrate_product_id_layout() {
+        let mut conn = fixture(PRODUCT_ID_LAYOUT);
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+        assert_eq!(device_ids(&conn), vec!["007a:0", "007a:0"]);
+        assert_eq!(
+            column::<i64>(&conn, "created_at"),
+            vec![1666728000000, 1666731600000]
+        );
+        assert_eq!(setting(&conn, "device_id"), "007a:0");
+        let devices: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM devices WHERE id = '007a:0'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(devices, 1);
+    }
+
+    #[test]
+    fn test_migrate_device_id_layout() {
+        let mut conn = fixture(DEVICE_ID_LAYOUT);
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+        assert_eq!(device_ids(&conn), vec!["PM2012H0001"]);
+        assert_eq!(column::<i64>(&conn, "created_at"), vec![1666728000000]);
+        assert_eq!(setting(&conn, "device_id"), "PM2012H0001");
+    }
+
+    #[test]
+    fn test_refuse_newer_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(RazermonError::NewerSchema(version)) if version == MIGRATIONS.len() + 1
+        ));
+    }
+
+    #[test]
+    fn test_failed_migration_rolls_back() {
+        fn broken(tx: &Transaction) -> rusqlite::Result<()> {
+            tx.execute_batch("ALTER TABLE battery ADD COLUMN raw INTEGER; SELECT * FROM missing;")
+        }
+
+        let mut conn = fixture(PRODUCT_ID_LAYOUT);
+        assert!(run_migrations(&mut conn, &[create_tables, add_device_ids, broken]).is_err());
+        assert_eq!(user_version(&conn), 2);
+        assert_eq!(device_ids(&conn).len(), 3);
+        let has_raw: bool = conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM pragma_table_info('battery') WHERE name = 'raw')",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(!has_raw);
     }
 }
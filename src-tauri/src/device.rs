@@ -0,0 +1,409 @@
+use std::sync::{Arc, Mutex};
+
+use razer_driver_rs::{
+    razer_device::{RazerDevice, RazerDeviceType},
+    scan_for_devices,
+};
+
+use crate::database::Database;
+use crate::error::{RazermonError, Result};
+
+pub(crate) type SharedSource = Arc<dyn DeviceSource>;
+
+/// A connected device, as reported by a [`DeviceSource`]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct DeviceInfo {
+    /// Stable identity of the device, see [`assign_ids`]
+    pub id: String,
+    pub product_id: u16,
+    pub serial: Option<String>,
+    pub name: String,
+}
+
+impl DeviceInfo {
+    pub fn new(product_id: u16, serial: Option<String>, name: &str) -> Self {
+        Self {
+            id: String::new(),
+            product_id,
+            serial: serial.filter(|s| !s.is_empty()),
+            name: name.to_string(),
+        }
+    }
+
+    /// Records the device in the `devices` table
+    ///
+    /// History recorded before devices were identified by serial, or by port, is keyed on the
+    /// fallback id of the first device with that product id. The first time a device with a
+    /// stable id shows up, it takes over that history.
+    pub fn save(&self, db: &Database) -> Result<()> {
+        db.with(|conn| self.save_with(conn))
+    }
+
+    /// Whether the id only tells the device's position, see [`fallback_id`]
+    fn has_fallback_id(&self) -> bool {
+        let prefix = format!("{:04x}:", self.product_id);
+        let index = self.id.strip_prefix(&prefix);
+        index
+            .and_then(|index| index.parse::<usize>().ok())
+            .is_some()
+    }
+
+    fn save_with(&self, conn: &mut rusqlite::Connection) -> Result<()> {
+        let tx = conn.transaction()?;
+        if !self.has_fallback_id() {
+            let legacy_id = fallback_id(self.product_id, 0);
+            let exists: bool = tx.query_row(
+                "SELECT EXISTS(SELECT 1 FROM devices WHERE id = ?1)",
+                [&self.id],
+                |row| row.get(0),
+            )?;
+            let adopted = !exists
+                && tx.execute(
+                    "UPDATE devices SET id = ?1 WHERE id = ?2 AND serial IS NULL",
+                    (&self.id, &legacy_id),
+                )? > 0;
+            if adopted {
+                tx.execute(
+                    "UPDATE battery SET device_id = ?1 WHERE device_id = ?2",
+                    (&self.id, &legacy_id),
+                )?;
+                tx.execute(
+                    "UPDATE settings SET value = ?1 WHERE key = 'device_id' AND value = ?2",
+                    (&self.id, &legacy_id),
+                )?;
+            }
+        }
+        tx.execute(
+            "INSERT INTO devices (id, product_id, serial, name) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(id) DO UPDATE SET product_id = ?2, serial = ?3, name = ?4",
+            (&self.id, &self.product_id, &self.serial, &self.name),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Id used for devices that don't report a serial number: the product id plus the position of
+/// the device among the connected devices with the same product id
+pub(crate) fn fallback_id(product_id: u16, index: usize) -> String {
+    format!("{:04x}:{}", product_id, index)
+}
+
+/// Fills in the id of each device, using the serial number when there is one
+pub(crate) fn assign_ids(devices: &mut [DeviceInfo]) {
+    let mut seen: Vec<u16> = vec![];
+    for device in devices.iter_mut() {
+        device.id = match &device.serial {
+            Some(serial) => serial.clone(),
+            None => {
+                let index = seen.iter().filter(|id| **id == device.product_id).count();
+                seen.push(device.product_id);
+                fallback_id(device.product_id, index)
+            }
+        };
+    }
+}
+
+/// Everything the app needs to know about the hardware goes through this trait, so the polling
+/// and persistence logic can run against a fake set of devices
+pub(crate) trait DeviceSource: Send + Sync {
+    /// Lists the devices currently connected
+    fn devices(&self) -> Result<Vec<DeviceInfo>>;
+
+    /// Reads the battery charge of a device, from 0 to 100
+    fn battery_charge(&self, device: &DeviceInfo) -> Result<u8>;
+
+    /// Reads whether the device is currently charging
+    fn charging_status(&self, device: &DeviceInfo) -> Result<bool>;
+
+    /// Finds a connected device by its id
+    fn identify(&self, id: &str) -> Result<Option<DeviceInfo>> {
+        Ok(self.devices()?.into_iter().find(|d| d.id == id))
+    }
+}
+
+/// Converts the raw 0-255 charge level reported by the drivers into a percentage
+pub(crate) fn charge_percentage(raw: u8) -> u8 {
+    (raw as f32 / 255.0 * 100.0).round() as u8
+}
+
+/// Returns the device source for the current platform, preferring the OpenRazer driver on Linux
+/// when it is loaded
+pub(crate) fn default_source() -> SharedSource {
+    #[cfg(target_os = "linux")]
+    {
+        let sysfs = crate::sysfs::SysfsSource::default();
+        if sysfs.is_available() {
+            return Arc::new(sysfs);
+        }
+    }
+    Arc::new(RazerSource)
+}
+
+/// Reads devices through `razer_driver_rs`
+pub(crate) struct RazerSource;
+
+impl RazerSource {
+    fn scan(product_id: Option<u16>) -> Result<Vec<RazerDevice<RazerDeviceType>>> {
+        match scan_for_devices(product_id) {
+            Ok(res) => Ok(res.devices),
+            Err(err) => Err(RazermonError::read_failed("devices", err)),
+        }
+    }
+
+    fn info(devices: &[RazerDevice<RazerDeviceType>]) -> Vec<DeviceInfo> {
+        // razer_driver_rs doesn't expose serial numbers, so devices are told apart by position
+        let mut infos = devices
+            .iter()
+            .map(|d| DeviceInfo::new(d.device.product_id(), None, &d.name))
+            .collect::<Vec<_>>();
+        assign_ids(&mut infos);
+        infos
+    }
+
+    fn find(&self, device: &DeviceInfo) -> Result<RazerDevice<RazerDeviceType>> {
+        let devices = Self::scan(Some(device.product_id))?;
+        let position = Self::info(&devices).iter().position(|d| d.id == device.id);
+        match position {
+            Some(position) => Ok(devices.into_iter().nth(position).unwrap()),
+            None => Err(RazermonError::DeviceUnavailable(device.id.clone())),
+        }
+    }
+}
+
+impl DeviceSource for RazerSource {
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        Ok(Self::info(&Self::scan(None)?))
+    }
+
+    fn battery_charge(&self, device: &DeviceInfo) -> Result<u8> {
+        let raw = self
+            .find(device)?
+            .get_battery_charge()
+            .map_err(|err| RazermonError::read_failed(&device.name, err))?;
+        Ok(charge_percentage(raw))
+    }
+
+    fn charging_status(&self, device: &DeviceInfo) -> Result<bool> {
+        let status = self
+            .find(device)?
+            .get_charging_status()
+            .map_err(|err| RazermonError::read_failed(&device.name, err))?;
+        Ok(status == 1)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FakeDevice {
+    info: DeviceInfo,
+    percentage: u8,
+    charging: bool,
+    failing: bool,
+}
+
+/// In-memory device source, used to exercise polling and persistence without any hardware
+#[allow(dead_code)]
+#[derive(Default)]
+pub(crate) struct FakeSource {
+    devices: Mutex<Vec<FakeDevice>>,
+}
+
+#[allow(dead_code)]
+impl FakeSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Plugs in a device and returns its id
+    pub fn connect(
+        &self,
+        product_id: u16,
+        serial: Option<&str>,
+        name: &str,
+        percentage: u8,
+        charging: bool,
+    ) -> String {
+        let mut devices = self.devices.lock().unwrap();
+        devices.push(FakeDevice {
+            info: DeviceInfo::new(product_id, serial.map(String::from), name),
+            percentage,
+            charging,
+            failing: false,
+        });
+        Self::assign_ids(&mut devices);
+        devices.last().unwrap().info.id.clone()
+    }
+
+    pub fn disconnect(&self, id: &str) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.retain(|d| d.info.id != id);
+        Self::assign_ids(&mut devices);
+    }
+
+    pub fn set_battery(&self, id: &str, percentage: u8, charging: bool) {
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(device) = devices.iter_mut().find(|d| d.info.id == id) {
+            device.percentage = percentage;
+            device.charging = charging;
+        }
+    }
+
+    /// Makes every read of the device fail, like a flaky HID read would
+    pub fn set_failing(&self, id: &str, failing: bool) {
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(device) = devices.iter_mut().find(|d| d.info.id == id) {
+            device.failing = failing;
+        }
+    }
+
+    fn assign_ids(devices: &mut [FakeDevice]) {
+        let mut infos = devices.iter().map(|d| d.info.clone()).collect::<Vec<_>>();
+        assign_ids(&mut infos);
+        for (device, info) in devices.iter_mut().zip(infos) {
+            device.info = info;
+        }
+    }
+
+    fn read<T>(&self, device: &DeviceInfo, f: impl Fn(&FakeDevice) -> T) -> Result<T> {
+        let devices = self.devices.lock().unwrap();
+        match devices.iter().find(|d| d.info.id == device.id) {
+            Some(fake) if fake.failing => Err(RazermonError::read_failed(&device.name, "timeout")),
+            Some(device) => Ok(f(device)),
+            None => Err(RazermonError::DeviceUnavailable(device.id.clone())),
+        }
+    }
+}
+
+impl DeviceSource for FakeSource {
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        let devices = self.devices.lock().unwrap();
+        Ok(devices.iter().map(|d| d.info.clone()).collect())
+    }
+
+    fn battery_charge(&self, device: &DeviceInfo) -> Result<u8> {
+        self.read(device, |d| d.percentage)
+    }
+
+    fn charging_status(&self, device: &DeviceInfo) -> Result<bool> {
+        self.read(device, |d| d.charging)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_charge_percentage() {
+        assert_eq!(charge_percentage(0), 0);
+        assert_eq!(charge_percentage(128), 50);
+        assert_eq!(charge_percentage(255), 100);
+    }
+
+    #[test]
+    fn test_assign_ids() {
+        let mut devices = vec![
+            DeviceInfo::new(0x7a, None, "Viper Ultimate"),
+            DeviceInfo::new(0x7a, Some("PM2012H0001".to_string()), "Viper Ultimate"),
+            DeviceInfo::new(0x7a, None, "Viper Ultimate"),
+            DeviceInfo::new(0x226, Some("".to_string()), "Huntsman Elite"),
+        ];
+        assign_ids(&mut devices);
+        let ids = devices.iter().map(|d| d.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["007a:0", "PM2012H0001", "007a:1", "0226:0"]);
+    }
+
+    #[test]
+    fn test_save_adopts_legacy_history() {
+        let db = Database::open_in_memory().unwrap();
+        db.with(|conn| {
+            conn.execute_batch(
+                "INSERT INTO devices (id, product_id) VALUES ('007a:0', 122);
+                INSERT INTO battery (created_at, device_id, product_id, percentage, charging) VALUES (1666728000000, '007a:0', 122, 80, 0);
+                INSERT INTO settings (key, value) VALUES ('device_id', '007a:0');",
+            )?;
+            Ok(())
+        })
+        .unwrap();
+
+        let mut mouse = DeviceInfo::new(0x7a, Some("PM2012H0001".to_string()), "Viper Ultimate");
+        let mut other_mouse =
+            DeviceInfo::new(0x7a, Some("PM2012H0002".to_string()), "Viper Ultimate");
+        assign_ids(std::slice::from_mut(&mut mouse));
+        assign_ids(std::slice::from_mut(&mut other_mouse));
+        mouse.save(&db).unwrap();
+        other_mouse.save(&db).unwrap();
+
+        let count = |sql: &str| -> i64 {
+            db.with(|conn| Ok(conn.query_row(sql, (), |row| row.get(0))?))
+                .unwrap()
+        };
+        assert_eq!(count("SELECT COUNT(*) FROM devices"), 2);
+        assert_eq!(
+            count("SELECT COUNT(*) FROM battery WHERE device_id = 'PM2012H0001'"),
+            1
+        );
+        assert_eq!(
+            crate::settings::get(&db, "device_id").unwrap(),
+            Some("PM2012H0001".to_string())
+        );
+    }
+
+    #[test]
+    fn test_save_by_port_adopts_legacy_history() {
+        let db = Database::open_in_memory().unwrap();
+        let mut first = DeviceInfo::new(0x7a, None, "Viper Ultimate");
+        first.id = fallback_id(0x7a, 0);
+        first.save(&db).unwrap();
+        db.with(|conn| {
+            conn.execute(
+                "INSERT INTO battery (created_at, device_id, product_id, percentage, charging) VALUES (1666728000000, '007a:0', 122, 80, 0)",
+                (),
+            )?;
+            Ok(())
+        })
+        .unwrap();
+
+        // still told apart by position
+        let mut second = DeviceInfo::new(0x7a, None, "Viper Ultimate");
+        second.id = fallback_id(0x7a, 1);
+        second.save(&db).unwrap();
+        let mut by_port = DeviceInfo::new(0x7a, None, "Viper Ultimate");
+        by_port.id = "007a:1-2".to_string();
+        by_port.save(&db).unwrap();
+
+        let history = db
+            .with(|conn| {
+                Ok(conn.query_row("SELECT device_id FROM battery", (), |row| {
+                    row.get::<_, String>(0)
+                })?)
+            })
+            .unwrap();
+        assert_eq!(history, "007a:1-2");
+    }
+
+    #[test]
+    fn test_fake_source() {
+        let source = FakeSource::new();
+        let mouse_id = source.connect(1, Some("M1"), "Mouse", 80, false);
+        let other_mouse_id = source.connect(1, Some("M2"), "Mouse", 20, false);
+        source.connect(2, None, "Keyboard", 40, true);
+
+        let mouse = source.identify(&mouse_id).unwrap().unwrap();
+        assert_eq!(mouse.name, "Mouse");
+        assert_eq!(source.battery_charge(&mouse).unwrap(), 80);
+        assert!(!source.charging_status(&mouse).unwrap());
+
+        let other_mouse = source.identify(&other_mouse_id).unwrap().unwrap();
+        assert_eq!(source.battery_charge(&other_mouse).unwrap(), 20);
+
+        source.set_battery(&mouse_id, 79, true);
+        assert_eq!(source.battery_charge(&mouse).unwrap(), 79);
+        assert!(source.charging_status(&mouse).unwrap());
+
+        source.disconnect(&mouse_id);
+        assert_eq!(source.devices().unwrap().len(), 2);
+        assert_eq!(source.identify(&mouse_id).unwrap(), None);
+        assert!(source.battery_charge(&mouse).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt;
+
+pub(crate) type Result<T> = std::result::Result<T, RazermonError>;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum RazermonError {
+    #[error("Device {0} is not connected")]
+    DeviceUnavailable(String),
+
+    #[error("Couldn't read {device}: {message}")]
+    ReadFailed { device: String, message: String },
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+
+    #[error("Couldn't parse {0}")]
+    Parse(String),
+
+    #[error("The database was created by a newer version of razermon (schema version {0})")]
+    NewerSchema(usize),
+}
+
+impl RazermonError {
+    pub fn read_failed(device: &str, err: impl fmt::Display) -> Self {
+        Self::ReadFailed {
+            device: device.to_string(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for RazermonError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Storage(err.to_string())
+    }
+}
+
+impl From<serde_rusqlite::Error> for RazermonError {
+    fn from(err: serde_rusqlite::Error) -> Self {
+        Self::Storage(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for RazermonError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Storage(err.to_string())
+    }
+}
+
+/// Commands hand errors over to the frontend as their message
+impl serde::Serialize for RazermonError {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
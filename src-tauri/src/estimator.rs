@@ -0,0 +1,195 @@
+use chrono::{DateTime, Utc};
+
+use crate::database::Database;
+use crate::error::{RazermonError, Result};
+use crate::settings;
+
+/// Smoothing factor of [`Ewma`] when the setting doesn't give one
+const DEFAULT_EWMA_ALPHA: f64 = 0.2;
+
+/// Length of the [`SlidingWindow`] when the setting doesn't give one
+const DEFAULT_WINDOW_DAYS: i64 = 14;
+
+/// How long it took the battery to lose 1% at some point
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Measurement {
+    /// When the percentage dropped
+    pub at: DateTime<Utc>,
+    /// The level the battery dropped from
+    pub percentage: u8,
+    pub seconds: i64,
+}
+
+/// Turns discharge measurements into the expected number of seconds per percent
+pub(crate) trait Estimator: Send + Sync {
+    /// Estimates from measurements ordered oldest first, `None` when there aren't enough of them
+    fn estimate(&self, measurements: &[Measurement]) -> Option<i64>;
+}
+
+/// Plain average of every measurement
+pub(crate) struct Mean;
+
+impl Estimator for Mean {
+    fn estimate(&self, measurements: &[Measurement]) -> Option<i64> {
+        if measurements.is_empty() {
+            return None;
+        }
+        let total = measurements.iter().map(|m| m.seconds).sum::<i64>();
+        Some(total / measurements.len() as i64)
+    }
+}
+
+/// Exponentially weighted moving average, so recent measurements count the most
+pub(crate) struct Ewma {
+    /// Weight of each new measurement, from 0 to 1
+    pub alpha: f64,
+}
+
+impl Estimator for Ewma {
+    fn estimate(&self, measurements: &[Measurement]) -> Option<i64> {
+        let (first, rest) = measurements.split_first()?;
+        let average = rest.iter().fold(first.seconds as f64, |average, m| {
+            self.alpha * m.seconds as f64 + (1.0 - self.alpha) * average
+        });
+        Some(average.round() as i64)
+    }
+}
+
+/// Average of the measurements taken during the last days of history
+pub(crate) struct SlidingWindow {
+    pub days: i64,
+}
+
+impl Estimator for SlidingWindow {
+    fn estimate(&self, measurements: &[Measurement]) -> Option<i64> {
+        // counted back from the latest measurement, so a device left in a drawer keeps its estimate
+        let latest = measurements.last()?.at;
+        let start = latest - chrono::Duration::days(self.days);
+        let first = measurements.partition_point(|m| m.at < start);
+        Mean.estimate(&measurements[first..])
+    }
+}
+
+/// Median of every measurement, so a few outliers, like a night the app missed, don't skew it
+pub(crate) struct Median;
+
+impl Estimator for Median {
+    fn estimate(&self, measurements: &[Measurement]) -> Option<i64> {
+        if measurements.is_empty() {
+            return None;
+        }
+        let mut seconds = measurements.iter().map(|m| m.seconds).collect::<Vec<_>>();
+        seconds.sort_unstable();
+        let middle = seconds.len() / 2;
+        if seconds.len() % 2 == 0 {
+            Some((seconds[middle - 1] + seconds[middle]) / 2)
+        } else {
+            Some(seconds[middle])
+        }
+    }
+}
+
+/// Builds the estimator named by a setting value: `mean`, `ewma`, `window` or `median`, with an
+/// optional parameter like `ewma:0.3` or `window:7`
+pub(crate) fn parse(value: &str) -> Result<Box<dyn Estimator>> {
+    let invalid = || RazermonError::Parse(format!("estimator {:?}", value));
+    let (name, param) = match value.split_once(':') {
+        Some((name, param)) => (name, Some(param)),
+        None => (value, None),
+    };
+    let estimator: Box<dyn Estimator> = match (name, param) {
+        ("mean", None) => Box::new(Mean),
+        ("median", None) => Box::new(Median),
+        ("ewma", param) => {
+            let alpha = match param {
+                Some(param) => param.parse::<f64>().map_err(|_| invalid())?,
+                None => DEFAULT_EWMA_ALPHA,
+            };
+            if !(alpha > 0.0 && alpha <= 1.0) {
+                return Err(invalid());
+            }
+            Box::new(Ewma { alpha })
+        }
+        ("window", param) => {
+            let days = match param {
+                Some(param) => param.parse::<i64>().map_err(|_| invalid())?,
+                None => DEFAULT_WINDOW_DAYS,
+            };
+            if days <= 0 {
+                return Err(invalid());
+            }
+            Box::new(SlidingWindow { days })
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(estimator)
+}
+
+/// The estimator picked in the `estimator` setting, the plain mean by default
+pub(crate) fn load(db: &Database) -> Result<Box<dyn Estimator>> {
+    match settings::get(db, "estimator")? {
+        Some(value) => parse(&value),
+        None => Ok(Box::new(Mean)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::BatteryData;
+
+    fn measurements(seconds: &[(&str, i64)]) -> Vec<Measurement> {
+        seconds
+            .iter()
+            .map(|(at, seconds)| Measurement {
+                at: BatteryData::new(1, at, 0, false).created_at,
+                percentage: 50,
+                seconds: *seconds,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_estimators() {
+        let measurements = measurements(&[
+            ("2022-01-01 20:00:00", 3000),
+            ("2022-01-02 20:00:00", 3000),
+            ("2022-01-20 20:00:00", 30000),
+            ("2022-01-21 20:00:00", 2000),
+            ("2022-01-22 20:00:00", 2000),
+        ]);
+
+        assert_eq!(Mean.estimate(&measurements), Some(8000));
+        assert_eq!(Median.estimate(&measurements), Some(3000));
+        assert_eq!(Median.estimate(&measurements[..4]), Some(3000));
+        assert_eq!(
+            SlidingWindow { days: 7 }.estimate(&measurements),
+            Some(11333)
+        );
+        assert_eq!(Ewma { alpha: 0.5 }.estimate(&measurements), Some(5625));
+        assert_eq!(Ewma { alpha: 1.0 }.estimate(&measurements), Some(2000));
+
+        for estimator in ["mean", "ewma", "window", "median"] {
+            assert_eq!(parse(estimator).unwrap().estimate(&[]), None);
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let db = Database::open_in_memory().unwrap();
+        let measurements =
+            measurements(&[("2022-01-01 20:00:00", 1000), ("2022-01-02 20:00:00", 5000)]);
+        assert_eq!(load(&db).unwrap().estimate(&measurements), Some(3000));
+
+        settings::set(&db, "estimator", "ewma:0.25").unwrap();
+        assert_eq!(load(&db).unwrap().estimate(&measurements), Some(2000));
+
+        settings::set(&db, "estimator", "window:1").unwrap();
+        assert_eq!(load(&db).unwrap().estimate(&measurements), Some(3000));
+
+        for invalid in ["average", "ewma:2", "window:-1", "median:3"] {
+            settings::set(&db, "estimator", invalid).unwrap();
+            assert!(load(&db).is_err());
+        }
+    }
+}
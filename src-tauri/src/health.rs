@@ -0,0 +1,310 @@
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::database::Database;
+use crate::error::{RazermonError, Result};
+use crate::human_display::HumanDuration;
+use crate::sessions::{self, Session};
+use crate::settings;
+
+/// Capacity, as a percentage of the original one, below which the battery is reported as
+/// degraded when the `health_warning_percent` setting isn't set
+const DEFAULT_WARNING_PERCENT: i64 = 80;
+
+/// Discharges shorter than this many percents are too noisy to say anything about the battery
+const MIN_CYCLE_PERCENTS: u8 = 10;
+
+/// How many of the first cycles make up the original runtime
+const BASELINE_CYCLES: usize = 3;
+
+/// Cycles needed before the capacity is estimated
+const MIN_CYCLES: usize = 4;
+
+/// How far past the last cycle the trend is followed, so a device left unused for months isn't
+/// reported as worn out by the trend alone
+const MAX_EXTRAPOLATION_DAYS: i64 = 14;
+
+/// How long a full charge lasted during one discharge
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct Cycle {
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub started_at: DateTime<Utc>,
+    /// Seconds per percent of the discharge, times 100
+    pub runtime_seconds: i64,
+}
+
+/// How a device's battery held up over time
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub(crate) struct HealthReport {
+    pub device_id: String,
+    /// Oldest first
+    pub cycles: Vec<Cycle>,
+    /// How much the runtime changes each day, following a least squares fit of the cycles
+    pub trend_seconds_per_day: Option<f64>,
+    /// Runtime today, on the trend line, as a percentage of the runtime of the first cycles, up
+    /// to 100%. The trend isn't followed more than two weeks past the last cycle.
+    pub capacity_percent: Option<i64>,
+    /// The capacity dropped below the `health_warning_percent` setting
+    pub degraded: bool,
+}
+
+impl HealthReport {
+    /// Builds the report out of a device's sessions, in any order, estimating the capacity left
+    /// at `now`
+    pub fn from_sessions(
+        device_id: &str,
+        sessions: &[Session],
+        warning_percent: i64,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let mut cycles = sessions
+            .iter()
+            .filter(|s| !s.charging && !s.ongoing)
+            .filter(|s| s.start_percentage >= s.end_percentage + MIN_CYCLE_PERCENTS)
+            .filter_map(|s| {
+                Some(Cycle {
+                    started_at: s.started_at,
+                    runtime_seconds: s.seconds_per_percent? * 100,
+                })
+            })
+            .collect::<Vec<_>>();
+        cycles.sort_by_key(|c| c.started_at);
+
+        let trend = trend(&cycles);
+        let capacity_percent = match (trend, cycles.len() >= MIN_CYCLES) {
+            (Some((slope, intercept)), true) => {
+                let baseline = cycles[..BASELINE_CYCLES]
+                    .iter()
+                    .map(|c| c.runtime_seconds as f64)
+                    .sum::<f64>()
+                    / BASELINE_CYCLES as f64;
+                let last = cycles[cycles.len() - 1].started_at;
+                let at = now.min(last + Duration::days(MAX_EXTRAPOLATION_DAYS));
+                let current = intercept + slope * days(cycles[0].started_at, at);
+                Some(((current / baseline * 100.0).round() as i64).clamp(0, 100))
+            }
+            _ => None,
+        };
+
+        Self {
+            device_id: device_id.to_string(),
+            cycles,
+            trend_seconds_per_day: trend.map(|(slope, _)| slope),
+            capacity_percent,
+            degraded: matches!(capacity_percent, Some(p) if p < warning_percent),
+        }
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.device_id)?;
+        if self.cycles.is_empty() {
+            return writeln!(f, "  No full discharge recorded yet");
+        }
+        for cycle in &self.cycles {
+            writeln!(
+                f,
+                "  {}  {}",
+                cycle.started_at.format("%Y-%m-%d"),
+                Duration::seconds(cycle.runtime_seconds).as_human()
+            )?;
+        }
+        if let Some(slope) = self.trend_seconds_per_day {
+            let weekly = Duration::seconds((slope * 7.0).round() as i64);
+            let sign = if weekly < Duration::zero() { "-" } else { "+" };
+            let change = if weekly < Duration::zero() {
+                -weekly
+            } else {
+                weekly
+            };
+            writeln!(f, "  Trend: {}{} per week", sign, change.as_human())?;
+        }
+        if let Some(capacity) = self.capacity_percent {
+            writeln!(f, "  Capacity: {}% of original", capacity)?;
+        }
+        if self.degraded {
+            writeln!(
+                f,
+                "  ⚠️ The battery lost a lot of its capacity, consider replacing it"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn days(from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+    (to - from).num_seconds() as f64 / 86_400.0
+}
+
+/// Slope, in seconds per day, and intercept of the line fitting the runtimes, counting days from
+/// the first cycle
+fn trend(cycles: &[Cycle]) -> Option<(f64, f64)> {
+    let first = cycles.first()?;
+    let points = cycles
+        .iter()
+        .map(|c| {
+            (
+                days(first.started_at, c.started_at),
+                c.runtime_seconds as f64,
+            )
+        })
+        .collect::<Vec<_>>();
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let variance = points
+        .iter()
+        .map(|(x, _)| (x - mean_x).powi(2))
+        .sum::<f64>();
+    if variance == 0.0 {
+        return None;
+    }
+    let covariance = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum::<f64>();
+    let slope = covariance / variance;
+    Some((slope, mean_y - slope * mean_x))
+}
+
+/// Reads the `health_warning_percent` setting
+fn warning_percent(db: &Database) -> Result<i64> {
+    let Some(value) = settings::get(db, "health_warning_percent")? else {
+        return Ok(DEFAULT_WARNING_PERCENT);
+    };
+    match value.parse::<i64>() {
+        Ok(percent) if (1..=100).contains(&percent) => Ok(percent),
+        _ => Err(RazermonError::Parse(format!(
+            "health_warning_percent {:?}",
+            value
+        ))),
+    }
+}
+
+/// The health report of a device, from every session recorded for it
+pub(crate) fn report(db: &Database, device_id: &str) -> Result<HealthReport> {
+    let sessions = sessions::history(db, device_id)?;
+    Ok(HealthReport::from_sessions(
+        device_id,
+        &sessions,
+        warning_percent(db)?,
+        Utc::now(),
+    ))
+}
+
+/// Every device with battery history
+pub(crate) fn device_ids(db: &Database) -> Result<Vec<String>> {
+    db.with(|conn| {
+        let mut statement = conn.prepare_cached(
+            "SELECT DISTINCT device_id FROM battery WHERE device_id IS NOT NULL ORDER BY device_id",
+        )?;
+        let rows = statement.query_map((), |row| row.get(0))?;
+        Ok(rows.collect::<std::result::Result<Vec<_>, _>>()?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::BatteryData;
+
+    fn session(started_at: &str, seconds_per_percent: i64) -> Session {
+        let started_at = BatteryData::new(1, started_at, 0, false).created_at;
+        Session {
+            device_id: "M1".to_string(),
+            charging: false,
+            started_at,
+            ended_at: started_at + Duration::days(3),
+            start_percentage: 100,
+            end_percentage: 20,
+            idle_seconds: 0,
+            seconds_per_percent: Some(seconds_per_percent),
+            ended_on_charger: true,
+            ongoing: false,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let mut sessions = vec![
+            session("2022-01-01 09:00:00", 3600),
+            session("2022-01-11 09:00:00", 3400),
+            session("2022-01-21 09:00:00", 3200),
+            session("2022-01-31 09:00:00", 3000),
+            session("2022-02-10 09:00:00", 2800),
+        ];
+        // left out: charging, too short and still going
+        let mut charge = session("2022-01-05 09:00:00", 60);
+        charge.charging = true;
+        let mut short = session("2022-01-06 09:00:00", 60);
+        short.end_percentage = 95;
+        let mut ongoing = session("2022-02-20 09:00:00", 60);
+        ongoing.ongoing = true;
+        sessions.extend([charge, short, ongoing]);
+        sessions.reverse();
+
+        let now = BatteryData::new(1, "2022-02-10 09:00:00", 0, false).created_at;
+        let report = HealthReport::from_sessions("M1", &sessions, 80, now);
+        assert_eq!(report.cycles.len(), 5);
+        assert_eq!(report.cycles[0].runtime_seconds, 360000);
+        assert_eq!(report.trend_seconds_per_day, Some(-2000.0));
+        // 280000s on the trend line against 340000s for the first three cycles
+        assert_eq!(report.capacity_percent, Some(82));
+        assert!(!report.degraded);
+        assert!(HealthReport::from_sessions("M1", &sessions, 85, now).degraded);
+
+        // 260000s on the trend line ten days after the last cycle
+        let later = now + Duration::days(10);
+        let report = HealthReport::from_sessions("M1", &sessions, 80, later);
+        assert_eq!(report.capacity_percent, Some(76));
+        assert!(report.degraded);
+        // 252000s two weeks after the last cycle, however long the device sat unused since
+        let report = HealthReport::from_sessions("M1", &sessions, 70, now + Duration::days(365));
+        assert_eq!(report.capacity_percent, Some(74));
+        assert!(!report.degraded);
+        let report = HealthReport::from_sessions("M1", &sessions, 80, now);
+
+        let output = report.to_string();
+        assert!(output.contains("  2022-01-01  4d 4h\n"));
+        assert!(output.contains("  Trend: -3h53m per week\n"));
+        assert!(output.contains("  Capacity: 82% of original\n"));
+    }
+
+    #[test]
+    fn test_capacity_is_capped() {
+        // lasting longer over time
+        let sessions = [
+            session("2022-01-01 09:00:00", 3000),
+            session("2022-01-11 09:00:00", 3200),
+            session("2022-01-21 09:00:00", 3400),
+            session("2022-01-31 09:00:00", 3600),
+        ];
+        let now = BatteryData::new(1, "2022-02-10 09:00:00", 0, false).created_at;
+        let report = HealthReport::from_sessions("M1", &sessions, 80, now);
+        assert_eq!(report.capacity_percent, Some(100));
+    }
+
+    #[test]
+    fn test_not_enough_cycles() {
+        let sessions = [session("2022-01-01 09:00:00", 3600)];
+        let report = HealthReport::from_sessions("M1", &sessions, 80, Utc::now());
+        assert_eq!(report.trend_seconds_per_day, None);
+        assert_eq!(report.capacity_percent, None);
+        assert!(!report.degraded);
+
+        let report = HealthReport::from_sessions("M1", &[], 80, Utc::now());
+        assert_eq!(report.to_string(), "M1\n  No full discharge recorded yet\n");
+    }
+
+    #[test]
+    fn test_warning_percent() {
+        let db = Database::open_in_memory().unwrap();
+        assert_eq!(warning_percent(&db).unwrap(), 80);
+        settings::set(&db, "health_warning_percent", "70").unwrap();
+        assert_eq!(warning_percent(&db).unwrap(), 70);
+        settings::set(&db, "health_warning_percent", "150").unwrap();
+        assert!(report(&db, "M1").is_err());
+    }
+}
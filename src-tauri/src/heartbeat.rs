@@ -0,0 +1,180 @@
+use std::{sync::atomic::Ordering, thread, time::Duration};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::database::Database;
+use crate::error::Result;
+use crate::watcher::StopHandle;
+
+/// How often the current observation is extended
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A heartbeat arriving this long after the previous one means the computer was suspended in
+/// between, since the thread never sleeps that long on its own
+const SUSPEND_THRESHOLD_SECONDS: i64 = 60;
+
+/// A stretch of time during which razermon wasn't running, or the computer was suspended, so
+/// nothing was recorded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Gap {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// How much of the time between `from` and `to` falls into gaps
+pub(crate) fn unobserved(gaps: &[Gap], from: DateTime<Utc>, to: DateTime<Utc>) -> chrono::Duration {
+    gaps.iter()
+        .filter(|gap| gap.start < to && gap.end > from)
+        .map(|gap| gap.end.min(to) - gap.start.max(from))
+        .fold(chrono::Duration::zero(), |total, overlap| total + overlap)
+}
+
+/// Records the periods razermon is watching in the `observations` table
+///
+/// Each row starts when the app starts, or when the computer resumes from suspend, and is
+/// extended on every heartbeat. Whatever lies between two rows wasn't observed.
+pub(crate) struct Heartbeat {
+    observation: i64,
+    last: DateTime<Utc>,
+}
+
+impl Heartbeat {
+    pub fn start(db: &Database, now: DateTime<Utc>) -> Result<Self> {
+        Ok(Self {
+            observation: Self::observe(db, now, "start")?,
+            last: now,
+        })
+    }
+
+    /// Extends the current observation up to `now`, starting a new one when the previous
+    /// heartbeat is too old to have been followed by uninterrupted watching
+    pub fn beat(&mut self, db: &Database, now: DateTime<Utc>) -> Result<()> {
+        if (now - self.last).num_seconds() > SUSPEND_THRESHOLD_SECONDS {
+            self.observation = Self::observe(db, now, "resume")?;
+        } else {
+            db.with(|conn| {
+                conn.prepare_cached("UPDATE observations SET ended_at = ?1 WHERE id = ?2")?
+                    .execute((now.timestamp_millis(), self.observation))?;
+                Ok(())
+            })?;
+        }
+        self.last = now;
+        Ok(())
+    }
+
+    fn observe(db: &Database, now: DateTime<Utc>, reason: &str) -> Result<i64> {
+        db.with(|conn| {
+            conn.prepare_cached(
+                "INSERT INTO observations (started_at, ended_at, reason) VALUES (?1, ?1, ?2)",
+            )?
+            .execute((now.timestamp_millis(), reason))?;
+            Ok(conn.last_insert_rowid())
+        })
+    }
+}
+
+/// Every stretch of time between two observations. Time before the first observation, recorded
+/// before razermon kept track of it, isn't considered a gap.
+pub(crate) fn gaps(db: &Database) -> Result<Vec<Gap>> {
+    let observations = db.with(|conn| {
+        let mut statement = conn
+            .prepare_cached("SELECT started_at, ended_at FROM observations ORDER BY started_at")?;
+        let rows =
+            statement.query_map((), |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+        Ok(rows.collect::<std::result::Result<Vec<_>, _>>()?)
+    })?;
+
+    let millis = |millis: i64| Utc.timestamp_millis_opt(millis).single();
+    Ok(observations
+        .windows(2)
+        .filter_map(|pair| {
+            let start = millis(pair[0].1)?;
+            let end = millis(pair[1].0)?;
+            (end > start).then_some(Gap { start, end })
+        })
+        .collect())
+}
+
+/// Starts an observation and keeps extending it until stopped
+pub(crate) fn spawn(db: Database) -> StopHandle {
+    let (handle, stopped) = StopHandle::new();
+
+    thread::spawn(move || {
+        let mut heartbeat = match Heartbeat::start(&db, Utc::now()) {
+            Ok(heartbeat) => heartbeat,
+            Err(err) => {
+                eprintln!("WARN: Couldn't record the app start: {}", err);
+                return;
+            }
+        };
+        while !stopped.load(Ordering::Relaxed) {
+            thread::sleep(HEARTBEAT_INTERVAL);
+            if let Err(err) = heartbeat.beat(&db, Utc::now()) {
+                eprintln!("WARN: Couldn't record heartbeat: {}", err);
+            }
+        }
+    });
+
+    handle
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::BatteryData;
+
+    fn at(created_at: &str) -> DateTime<Utc> {
+        BatteryData::new(1, created_at, 0, false).created_at
+    }
+
+    #[test]
+    fn test_gaps() {
+        let db = Database::open_in_memory().unwrap();
+        let mut heartbeat = Heartbeat::start(&db, at("2022-01-01 20:00:00")).unwrap();
+        heartbeat.beat(&db, at("2022-01-01 20:00:10")).unwrap();
+        heartbeat.beat(&db, at("2022-01-01 20:00:20")).unwrap();
+        // suspended overnight
+        heartbeat.beat(&db, at("2022-01-02 08:00:00")).unwrap();
+        heartbeat.beat(&db, at("2022-01-02 08:00:10")).unwrap();
+        // restarted
+        Heartbeat::start(&db, at("2022-01-02 09:00:00")).unwrap();
+
+        assert_eq!(
+            gaps(&db).unwrap(),
+            vec![
+                Gap {
+                    start: at("2022-01-01 20:00:20"),
+                    end: at("2022-01-02 08:00:00"),
+                },
+                Gap {
+                    start: at("2022-01-02 08:00:10"),
+                    end: at("2022-01-02 09:00:00"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unobserved() {
+        let gaps = [
+            Gap {
+                start: at("2022-01-01 21:00:00"),
+                end: at("2022-01-01 22:00:00"),
+            },
+            Gap {
+                start: at("2022-01-01 23:00:00"),
+                end: at("2022-01-02 01:00:00"),
+            },
+        ];
+        let unobserved = |from, to| unobserved(&gaps, at(from), at(to)).num_seconds();
+        assert_eq!(unobserved("2022-01-01 20:00:00", "2022-01-01 21:00:00"), 0);
+        assert_eq!(
+            unobserved("2022-01-01 20:00:00", "2022-01-02 00:00:00"),
+            7200
+        );
+        assert_eq!(
+            unobserved("2022-01-01 21:30:00", "2022-01-02 02:00:00"),
+            9000
+        );
+    }
+}
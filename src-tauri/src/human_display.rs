@@ -1,5 +1,6 @@
 use chrono::Duration;
 use std::fmt;
+use std::ops::Range;
 
 pub const MINUTE: i64 = 60;
 pub const HOUR: i64 = 3_600;
@@ -60,6 +61,28 @@ impl fmt::Display for HumanDurationDisplay {
     }
 }
 
+impl HumanDuration for Range<Duration> {
+    type Displayer = HumanRangeDisplay;
+    fn as_human(&self) -> Self::Displayer {
+        HumanRangeDisplay(self.clone())
+    }
+}
+
+/// Shows a range like "1d 20h – 2d 6h", or a single duration when both ends look the same
+pub struct HumanRangeDisplay(Range<Duration>);
+
+impl fmt::Display for HumanRangeDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = self.0.start.as_human().to_string();
+        let end = self.0.end.as_human().to_string();
+        if start == end {
+            write!(f, "{}", start)
+        } else {
+            write!(f, "{} – {}", start, end)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -99,4 +122,17 @@ mod tests {
         let duration = Duration::days(2) + Duration::hours(10) + Duration::minutes(2);
         assert_eq!(duration.as_human().to_string(), "2d 10h");
     }
+
+    #[test]
+    fn range() {
+        let range =
+            (Duration::days(1) + Duration::hours(20))..(Duration::days(2) + Duration::hours(6));
+        assert_eq!(range.as_human().to_string(), "1d 20h – 2d 6h");
+    }
+
+    #[test]
+    fn range_within_display_precision() {
+        let range = Duration::days(2)..(Duration::days(2) + Duration::minutes(20));
+        assert_eq!(range.as_human().to_string(), "2d");
+    }
 }
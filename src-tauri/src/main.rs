@@ -1,25 +1,67 @@
-#[cfg(any(target_os = "macos"))]
-use crate::battery::BatteryStatus;
+use crate::battery::{BatteryStatus, DeviceStatus};
 use battery::BatteryData;
-use razer_driver_rs::scan_for_devices;
-use std::{thread, time::Duration};
+use chrono::{Local, Utc};
+use curve::Prediction;
+use cycles::ChargeCycles;
+use database::{Database, Location};
+use device::{DeviceSource, SharedSource};
+use error::Result;
+use health::HealthReport;
+use monitor::Monitor;
+use sessions::Session;
+use stats::{UsageRange, UsageStats};
+use std::{sync::atomic::Ordering, thread, time::Duration};
 use tauri::{
-    api::notification::Notification, AppHandle, CustomMenuItem, Manager, RunEvent, SystemTray,
-    SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem,
+    api::notification::Notification, AppHandle, Manager, RunEvent, State, SystemTray,
+    SystemTrayEvent,
 };
+use watcher::{DeviceEvent, StopHandle};
 
+mod anomaly;
 mod battery;
+mod cli;
+mod curve;
+mod cycles;
 mod database;
+mod device;
+mod error;
+mod estimator;
+mod health;
+mod heartbeat;
 mod human_display;
+mod monitor;
+mod pattern;
+mod retention;
+mod schedule;
+mod sessions;
 mod settings;
+mod stats;
+#[cfg(target_os = "linux")]
+mod sysfs;
+mod tray;
+mod watcher;
 
 fn main() {
-    let product_id = load_product_id();
-    let menu = tray_menu(product_id);
-    let status = status(product_id);
+    let db = Location::resolve()
+        .and_then(|location| Database::open(&location))
+        .unwrap_or_else(|err| {
+            eprintln!("Error opening the database: {}", err);
+            std::process::exit(1);
+        });
+    if let Some(code) = cli::run(&db, std::env::args().skip(1)) {
+        std::process::exit(code);
+    }
+    let source = device::default_source();
+    let device_id = load_device_id(&db, source.as_ref()).unwrap_or_else(|err| {
+        eprintln!("Error loading the selected device: {}", err);
+        None
+    });
+    let (status, menu) = tray::build(&db, source.as_ref(), device_id.as_deref());
 
     #[allow(unused_mut)]
     let mut app = tauri::Builder::default()
+        .manage(db.clone())
+        .manage(source.clone())
         .system_tray(SystemTray::new().with_title(&status).with_menu(menu))
         .on_system_tray_event(|app, event| match event {
             SystemTrayEvent::RightClick {
@@ -32,22 +74,19 @@ fn main() {
                 window.set_focus().unwrap();
             }
             SystemTrayEvent::MenuItemClick { id, .. } => {
-                let item_handle = app.tray_handle().get_item(&id);
+                let db = app.state::<Database>();
+                let source = app.state::<SharedSource>();
                 match id.as_str() {
                     "battery" => {
-                        let product_id = settings::get("product_id").unwrap().unwrap();
-                        let status = BatteryStatus::get(product_id.parse().unwrap());
-                        if let Ok(Some(status)) = status {
-                            app.tray_handle()
-                                .get_item("battery")
-                                .set_title(status.to_string())
-                                .unwrap();
-                        } else {
-                            app.tray_handle()
-                                .get_item("battery")
-                                .set_title("No battery data".to_string())
-                                .unwrap();
-                        }
+                        let title = match settings::get(&db, "device_id") {
+                            Ok(Some(device_id)) => status_title(source.as_ref(), &device_id),
+                            Ok(None) => "No battery data".to_string(),
+                            Err(err) => err.to_string(),
+                        };
+                        app.tray_handle()
+                            .get_item("battery")
+                            .set_title(title)
+                            .unwrap();
                     }
                     "usage" => {
                         let window = app.get_window("main").unwrap();
@@ -55,9 +94,6 @@ fn main() {
                         window.eval("window.location.reload()").unwrap();
                         window.set_focus().unwrap();
                     }
-                    "no_devices" => {
-                        eprintln!("No devices clicked");
-                    }
                     "devtools" => {
                         #[cfg(debug_assertions)]
                         app.get_window("main").unwrap().open_devtools();
@@ -74,28 +110,8 @@ fn main() {
                         app.exit(0);
                     }
                     str => {
-                        if str.starts_with("device_") {
-                            let res = scan_for_devices(None).unwrap();
-                            let devices = res.devices;
-                            if !devices.is_empty() {
-                                let str_id = str.replace("device_", "");
-                                settings::set("product_id", &str_id).unwrap();
-                                let id: u16 = str_id.parse().unwrap();
-                                app.tray_handle();
-                                item_handle.set_selected(true).unwrap();
-                                for device_spec in devices {
-                                    if device_spec.device.product_id() != id {
-                                        app.tray_handle()
-                                            .get_item(&format!(
-                                                "device_{}",
-                                                device_spec.device.product_id()
-                                            ))
-                                            .set_selected(false)
-                                            .unwrap();
-                                    }
-                                }
-                                update_tray_display(app, id);
-                            }
+                        if let Some(id) = str.strip_prefix("device_") {
+                            select_device(app, &db, source.as_ref(), id);
                         }
                     }
                 }
@@ -110,9 +126,14 @@ fn main() {
         })
         .invoke_handler(tauri::generate_handler![
             charge_history,
-            selected_product_id,
+            selected_device_id,
             device_status,
             battery_stats,
+            device_sessions,
+            last_discharge,
+            today_forecast,
+            battery_health,
+            usage_stats,
         ])
         .build(tauri::generate_context!())
         .expect("error while building tauri application");
@@ -121,9 +142,9 @@ fn main() {
     // app.set_activation_policy(tauri::ActivationPolicy::Accessory);
 
     let handle = app.handle();
-    if let Some(product_id) = product_id {
-        start_updates(handle, product_id);
-    }
+    let _heartbeat = heartbeat::spawn(db.clone());
+    let _retention = retention::spawn(db.clone());
+    let _watcher = watch_devices(handle, db, source);
 
     app.run(move |_app_handle, e| {
         if let RunEvent::ExitRequested { api, .. } = &e {
@@ -133,235 +154,275 @@ fn main() {
 }
 
 #[tauri::command]
-fn selected_product_id() -> Option<u16> {
-    load_product_id()
+fn selected_device_id(
+    db: State<'_, Database>,
+    source: State<'_, SharedSource>,
+) -> Result<Option<String>> {
+    load_device_id(&db, source.as_ref())
 }
 
 #[tauri::command]
-fn device_status(product_id: u16) -> Option<BatteryStatus> {
-    BatteryStatus::get(product_id).unwrap()
+fn device_status(
+    db: State<'_, Database>,
+    source: State<'_, SharedSource>,
+    device_id: String,
+) -> Result<Option<DeviceStatus>> {
+    let Some(status) = BatteryStatus::get(source.as_ref(), &device_id)? else {
+        return Ok(None);
+    };
+    let cycles = cycles::count(&db, &device_id)?;
+    Ok(Some(DeviceStatus { status, cycles }))
 }
 
 #[tauri::command]
-fn charge_history(product_id: u16) -> Result<Vec<BatteryData>, String> {
-    match BatteryData::get(product_id) {
-        Ok(data) => Ok(data),
-        Err(err) => Err(err.to_string()),
-    }
+fn charge_history(db: State<'_, Database>, device_id: String) -> Result<Vec<BatteryData>> {
+    BatteryData::get(&db, &device_id)
 }
 
+/// Seconds per percent, remaining time, predicted range and charge cycles, see `battery_stats`
+type BatteryStats = (
+    Option<i64>,
+    Option<String>,
+    Option<Prediction>,
+    ChargeCycles,
+);
+
+/// Seconds per percent while discharging, along with either how long the battery is expected to
+/// last or, while charging, how long until it's full, the range the battery is expected to last
+/// in and the charge cycle counts
 #[tauri::command]
-fn battery_stats(product_id: u16) -> Result<Option<(i64, Option<String>)>, String> {
-    match BatteryData::get(product_id) {
-        Ok(data) => match BatteryData::consumption(&data) {
-            Some(consumption) => Ok(Some((consumption, remaining(Some(product_id))))),
-            None => Ok(None),
-        },
-        Err(err) => Err(err.to_string()),
-    }
+fn battery_stats(
+    db: State<'_, Database>,
+    source: State<'_, SharedSource>,
+    device_id: String,
+) -> Result<BatteryStats> {
+    let consumption = battery::consumption(&db, &device_id)?;
+    let (remaining, prediction) = match BatteryStatus::get(source.as_ref(), &device_id)? {
+        Some(status) => (tray::remaining(&db, &status)?, status.remaining(&db)?),
+        None => (None, None),
+    };
+    let cycles = cycles::count(&db, &device_id)?;
+    Ok((consumption, remaining, prediction, cycles))
 }
 
-fn status(product_id: Option<u16>) -> String {
-    if let Some(product_id) = product_id {
-        if let Ok(Some(status)) = BatteryStatus::get(product_id) {
-            status.to_string()
-        } else {
-            "".to_string()
-        }
-    } else {
-        "".to_string()
+#[tauri::command]
+fn device_sessions(db: State<'_, Database>, device_id: String) -> Result<Vec<Session>> {
+    sessions::history(&db, &device_id)
+}
+
+/// How long the battery lasted on its last full discharge, like "4d 6h"
+#[tauri::command]
+fn last_discharge(db: State<'_, Database>, device_id: String) -> Result<Option<String>> {
+    sessions::last_discharge(&db, &device_id)
+}
+
+/// How the device's battery held up over time, see `health::HealthReport`
+#[tauri::command]
+fn battery_health(db: State<'_, Database>, device_id: String) -> Result<HealthReport> {
+    health::report(&db, &device_id)
+}
+
+/// Awake and asleep time, wake-ups and drain of the device for each day or week, oldest first
+#[tauri::command]
+fn usage_stats(
+    db: State<'_, Database>,
+    device_id: String,
+    range: UsageRange,
+) -> Result<Vec<UsageStats>> {
+    stats::usage(&db, &device_id, range)
+}
+
+/// Whether the device's battery makes it through today's work hours, like "Will last through
+/// today" or "Charge before 15:40", if there are work hours left today
+#[tauri::command]
+fn today_forecast(
+    db: State<'_, Database>,
+    source: State<'_, SharedSource>,
+    device_id: String,
+) -> Result<Option<String>> {
+    let Some(status) = BatteryStatus::get(source.as_ref(), &device_id)? else {
+        return Ok(None);
+    };
+    let forecast = schedule::today(&db, &status)?;
+    Ok(forecast.map(|f| f.to_string()))
+}
+
+/// Title for a device in the tray, showing why it couldn't be read if that's the case
+fn status_title(source: &dyn DeviceSource, device_id: &str) -> String {
+    match BatteryStatus::get(source, device_id) {
+        Ok(Some(status)) => status.to_string(),
+        Ok(None) => "No battery data".to_string(),
+        Err(err) => format!("⚠️ {}", err),
     }
 }
 
-fn load_product_id() -> Option<u16> {
-    let product_id = settings::get("product_id").unwrap();
-    let product_id = match product_id {
-        Some(product_id) => Some(product_id.parse().unwrap()),
+fn load_device_id(db: &Database, source: &dyn DeviceSource) -> Result<Option<String>> {
+    let device_id = settings::get(db, "device_id")?;
+    let device_id = match device_id {
+        Some(device_id) => Some(device_id),
         None => {
-            let res = scan_for_devices(None).unwrap();
-            if res.devices.is_empty() {
-                return None;
-            }
-            let Some(device) = res.devices.get(0) else {
-                return None;
+            let devices = source.devices()?;
+            let Some(device) = devices.first() else {
+                return Ok(None);
             };
-            let product_id = device.device.product_id();
-            settings::set("product_id", &product_id.to_string()).unwrap();
-            Some(product_id)
+            settings::set(db, "device_id", &device.id)?;
+            Some(device.id.clone())
         }
     };
-    product_id
+    Ok(device_id)
+}
+
+/// Reacts to devices arriving and leaving: starts polling when the first device shows up, stops
+/// when the last one goes away, moves the selection off unplugged devices and rebuilds the tray
+fn watch_devices(handle: AppHandle, db: Database, source: SharedSource) -> StopHandle {
+    let mut updates: Option<StopHandle> = None;
+    watcher::spawn(source.clone(), move |event, devices| {
+        if let Err(err) = handle.emit_all(event.name(), event.device()) {
+            eprintln!("WARN: Couldn't emit {}: {}", event.name(), err);
+        }
+
+        let selected = settings::get(&db, "device_id").ok().flatten();
+        let selected_connected = devices.iter().any(|d| Some(&d.id) == selected.as_ref());
+        match &event {
+            DeviceEvent::Connected(device) => {
+                if updates.is_none() {
+                    updates = Some(start_updates(handle.clone(), db.clone(), source.clone()));
+                }
+                if !selected_connected || selected.as_ref() == Some(&device.id) {
+                    return select_device(&handle, &db, source.as_ref(), &device.id);
+                }
+            }
+            DeviceEvent::Disconnected(_) => match devices.first() {
+                Some(device) if !selected_connected => {
+                    return select_device(&handle, &db, source.as_ref(), &device.id);
+                }
+                Some(_) => {}
+                None => updates = None,
+            },
+        }
+        tray::refresh(&handle, &db, source.as_ref());
+    })
 }
 
-fn start_updates(handle: AppHandle, mut product_id: u16) {
-    let mut curr_percentage = BatteryStatus::last_status(product_id).unwrap().unwrap_or(0);
-    let mut notified = false;
-    let mut curr_product_id = product_id;
+fn select_device(handle: &AppHandle, db: &Database, source: &dyn DeviceSource, device_id: &str) {
+    if let Err(err) = settings::set(db, "device_id", device_id) {
+        eprintln!("WARN: Couldn't save the selected device: {}", err);
+    }
+    tray::refresh(handle, db, source);
+}
+
+fn start_updates(handle: AppHandle, db: Database, source: SharedSource) -> StopHandle {
+    let monitor_db = db.clone();
+    let mut monitor = Monitor::new(move |device| {
+        if let Err(err) = device.save(&monitor_db) {
+            eprintln!("WARN: Couldn't save device {}: {}", device.id, err);
+        }
+        BatteryStatus::last_status(&monitor_db, &device.id)
+            .ok()
+            .flatten()
+    });
+    let (updates, stopped) = StopHandle::new();
+    let mut last_failures: Vec<String> = vec![];
     thread::spawn(move || loop {
         thread::sleep(Duration::from_secs(5));
+        if stopped.load(Ordering::Relaxed) {
+            break;
+        }
 
-        let res = match scan_for_devices(None) {
-            Ok(result) => result,
+        let poll = match monitor.poll(source.as_ref()) {
+            Ok(poll) => poll,
             Err(err) => {
                 eprintln!("Error scanning for devices: {}", err);
+                tray::show_error(&handle, &err);
                 continue;
             }
         };
-        let devices = res.devices;
-        let device_connected = devices.iter().any(|d| d.device.product_id() == product_id);
-
-        if !device_connected {
-            let new_device = devices.get(0);
-            println!("Device connected: {:?}", new_device);
-            if let Some(new_device) = new_device {
-                product_id = new_device.device.product_id();
-                settings::set("product_id", &product_id.to_string()).unwrap();
-                update_tray_display(&handle, product_id);
-            } else {
-                // No devices found, update the tray display accordingly
-                handle.tray_handle().set_title("No devices found").unwrap();
-                handle
-                    .tray_handle()
-                    .get_item("remaining")
-                    .set_title("No devices found")
-                    .unwrap();
-                continue;
-            }
+        for (device, err) in &poll.failures {
+            eprintln!("Error getting battery status for {}: {}", device.name, err);
         }
 
-        match BatteryStatus::get_from_devices(&devices, product_id) {
-            Ok(Some(status)) => {
-                if status.percentage != curr_percentage || curr_product_id != product_id {
-                    curr_percentage = status.percentage;
-                    curr_product_id = product_id;
-                    handle.tray_handle().set_title(&status.to_string()).unwrap();
-                    let res = status.save();
-                    if res.is_err() {
-                        eprintln!("WARN: Couldn't save battery status");
+        for reading in &poll.readings {
+            let status = &reading.status;
+            if reading.changed {
+                if let Err(err) = status.save(&db) {
+                    eprintln!(
+                        "WARN: Couldn't save battery status for {}: {}",
+                        status.name, err
+                    );
+                }
+                if let Err(err) = sessions::record(&db, &status.device_id) {
+                    eprintln!(
+                        "WARN: Couldn't record sessions for {}: {}",
+                        status.name, err
+                    );
+                }
+                if let Err(err) = cycles::count(&db, &status.device_id) {
+                    eprintln!(
+                        "WARN: Couldn't count charge cycles for {}: {}",
+                        status.name, err
+                    );
+                }
+                match anomaly::check(&db, &status.device_id, Utc::now()) {
+                    Ok(Some(anomaly)) => {
+                        let res = Notification::new("org.fcoury.razermon")
+                            .icon("icons/128x128.png")
+                            .title("Abnormal battery drain")
+                            .body(format!("Your {} is {}.", status.name, anomaly))
+                            .show();
+                        if let Err(err) = res {
+                            eprintln!("WARN: Couldn't show notification: {}", err);
+                        }
                     }
-
-                    // notifies again at 5%
-                    if status.percentage < 5 {
-                        notified = false;
+                    Ok(None) => {}
+                    Err(err) => {
+                        eprintln!("WARN: Couldn't check the drain of {}: {}", status.name, err)
                     }
+                }
+            }
 
-                    // if battery is below threshold and not already notified
-                    if status.percentage < 10 && status.percentage != 0 && !notified {
-                        Notification::new("org.fcoury.razermon")
-                            .icon("icons/128x128.png")
-                            .title("Battery warning")
-                            .body("Your battery is running low.")
-                            .show()
-                            .unwrap();
-                        notified = true;
+            match schedule::morning_warning(&db, status, Local::now().naive_local()) {
+                Ok(Some(forecast)) => {
+                    let res = Notification::new("org.fcoury.razermon")
+                        .icon("icons/128x128.png")
+                        .title("Battery forecast")
+                        .body(format!(
+                            "Your {} won't last through the day. {}.",
+                            status.name, forecast
+                        ))
+                        .show();
+                    if let Err(err) = res {
+                        eprintln!("WARN: Couldn't show notification: {}", err);
                     }
                 }
+                Ok(None) => {}
+                Err(err) => eprintln!(
+                    "WARN: Couldn't forecast the day for {}: {}",
+                    status.name, err
+                ),
             }
-            Ok(None) => {
-                handle.tray_handle().set_title("No devices found").unwrap();
-                handle
-                    .tray_handle()
-                    .get_item("remaining")
-                    .set_title("No devices found")
-                    .unwrap();
-            }
-            Err(err) => {
-                eprintln!("Error getting battery status: {}", err);
-            }
-        }
-    });
-}
 
-fn remaining(product_id: Option<u16>) -> Option<String> {
-    if let Some(product_id) = product_id {
-        match BatteryStatus::get(product_id) {
-            Ok(Some(status)) => {
-                if let Ok(remaining) = status.fmt_remaining() {
-                    return remaining.map(|r| format!("{} remaining", r));
+            if reading.low_battery {
+                let res = Notification::new("org.fcoury.razermon")
+                    .icon("icons/128x128.png")
+                    .title("Battery warning")
+                    .body(format!("Your {} battery is running low.", status.name))
+                    .show();
+                if let Err(err) = res {
+                    eprintln!("WARN: Couldn't show notification: {}", err);
                 }
             }
-            Ok(None) => {}
-            Err(err) => {
-                eprintln!("Error getting battery status: {}", err);
-            }
         }
-    }
-    None
-}
-
-fn tray_menu(product_id: Option<u16>) -> SystemTrayMenu {
-    let mut menu = SystemTrayMenu::new();
-
-    let remaining = match remaining(product_id) {
-        Some(remaining) => remaining,
-        None => "Not enough data to calulate ETA yet".to_string(),
-    };
-
-    let mut remaining_item = CustomMenuItem::new("remaining", remaining);
-    remaining_item.enabled = false;
-    menu = menu
-        .add_item(remaining_item)
-        .add_native_item(SystemTrayMenuItem::Separator);
-
-    if let Some(product_id) = product_id {
-        let res = scan_for_devices(None).unwrap();
-        let devices = res.devices;
-        if devices.is_empty() {
-            return no_devices_menu(&menu);
-        }
-        menu = menu
-            .add_item(CustomMenuItem::new("usage", "Usage Chart..."))
-            .add_item(CustomMenuItem::new("notify", "Test Notification"))
-            .add_native_item(SystemTrayMenuItem::Separator);
-
-        for device_spec in devices {
-            let id = format!("device_{}", device_spec.device.product_id());
-            let mut item = CustomMenuItem::new(id, &device_spec.name);
-            item.selected = product_id == device_spec.device.product_id();
-            // item.enabled = device.has_battery(); TODO
-            menu = menu.add_item(item);
-        }
-
-        menu = menu
-            .add_native_item(SystemTrayMenuItem::Separator)
-            .add_item(CustomMenuItem::new("devtools", "Open DevTools"));
-    } else {
-        menu = no_devices_menu(&menu);
-    };
-
-    menu.add_native_item(SystemTrayMenuItem::Separator)
-        .add_item(CustomMenuItem::new("quit", "Quit"))
-}
 
-fn no_devices_menu(menu: &SystemTrayMenu) -> SystemTrayMenu {
-    let menu = menu.clone();
-    let mut item = CustomMenuItem::new("no_devices", "No devices found");
-    item.enabled = false;
-    menu.add_item(item)
-}
-
-fn update_tray_display(handle: &AppHandle, product_id: u16) {
-    match BatteryStatus::get(product_id) {
-        Ok(Some(status)) => {
-            handle.tray_handle().set_title(&status.to_string()).unwrap();
-        }
-        Ok(None) => {
-            handle.tray_handle().set_title("No battery data").unwrap();
+        // the menu shows every device, so it's rebuilt whenever any of them changes
+        let failures = poll
+            .failures
+            .iter()
+            .map(|(device, _)| device.id.clone())
+            .collect::<Vec<_>>();
+        if poll.readings.iter().any(|r| r.changed) || failures != last_failures {
+            tray::refresh(&handle, &db, source.as_ref());
         }
-        Err(err) => {
-            eprintln!("Error getting battery status: {}", err);
-            handle
-                .tray_handle()
-                .set_title("Error getting battery status")
-                .unwrap();
-        }
-    }
-
-    if let Some(remaining) = remaining(Some(product_id)) {
-        handle
-            .tray_handle()
-            .get_item("remaining")
-            .set_title(remaining)
-            .unwrap();
-    }
+        last_failures = failures;
+    });
+    updates
 }
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+
+use crate::battery::BatteryStatus;
+use crate::device::{DeviceInfo, DeviceSource};
+use crate::error::{RazermonError, Result};
+
+type LastStatus = Box<dyn Fn(&DeviceInfo) -> Option<u8> + Send>;
+
+/// What the monitor remembers about each connected device between ticks
+struct DeviceState {
+    percentage: u8,
+    /// Unknown until the device is read once, since only the percentage is recorded on its own
+    charging: Option<bool>,
+    notified: bool,
+}
+
+/// The result of sampling one device
+#[derive(Debug)]
+pub(crate) struct Reading {
+    pub status: BatteryStatus,
+    /// The percentage or charging state differs from the last one seen for this device, so it
+    /// should be saved
+    pub changed: bool,
+    /// The battery just crossed the low battery threshold and the user should be warned
+    pub low_battery: bool,
+}
+
+/// Everything seen during one poll
+#[derive(Debug, Default)]
+pub(crate) struct Poll {
+    pub readings: Vec<Reading>,
+    /// Devices that are connected but couldn't be read
+    pub failures: Vec<(DeviceInfo, RazermonError)>,
+}
+
+/// Samples every connected device, keeping track of their last percentage and low battery
+/// notification state
+pub(crate) struct Monitor {
+    devices: HashMap<String, DeviceState>,
+    last_status: LastStatus,
+}
+
+impl Monitor {
+    /// Creates a monitor, using `last_status` to find the last percentage recorded for a device
+    /// the first time it is seen
+    pub fn new(last_status: impl Fn(&DeviceInfo) -> Option<u8> + Send + 'static) -> Self {
+        Self {
+            devices: HashMap::new(),
+            last_status: Box::new(last_status),
+        }
+    }
+
+    pub fn poll(&mut self, source: &dyn DeviceSource) -> Result<Poll> {
+        let devices = source.devices()?;
+        self.devices
+            .retain(|id, _| devices.iter().any(|d| d.id == *id));
+
+        let mut poll = Poll::default();
+        for device in &devices {
+            let status = match BatteryStatus::get_from_devices(source, &devices, &device.id) {
+                Ok(Some(status)) => status,
+                Ok(None) => continue,
+                Err(err) => {
+                    poll.failures.push((device.clone(), err));
+                    continue;
+                }
+            };
+
+            let last_status = &self.last_status;
+            let state = self
+                .devices
+                .entry(device.id.clone())
+                .or_insert_with(|| DeviceState {
+                    percentage: last_status(device).unwrap_or(0),
+                    charging: None,
+                    notified: false,
+                });
+
+            let plugged = matches!(state.charging, Some(charging) if charging != status.charging);
+            state.charging = Some(status.charging);
+            let changed = status.percentage != state.percentage || plugged;
+            let mut low_battery = false;
+            if status.percentage != state.percentage {
+                state.percentage = status.percentage;
+
+                // notifies again at 5%
+                if status.percentage < 5 {
+                    state.notified = false;
+                }
+
+                // if battery is below threshold and not already notified
+                if status.percentage < 10 && status.percentage != 0 && !state.notified {
+                    state.notified = true;
+                    low_battery = true;
+                }
+            }
+
+            poll.readings.push(Reading {
+                status,
+                changed,
+                low_battery,
+            });
+        }
+
+        Ok(poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::FakeSource;
+
+    #[test]
+    fn test_poll_every_device() {
+        let source = FakeSource::new();
+        let mouse = source.connect(1, Some("M1"), "Mouse", 80, false);
+        let other_mouse = source.connect(1, Some("M2"), "Mouse", 60, false);
+        source.connect(2, None, "Keyboard", 50, false);
+        let mut monitor = Monitor::new(|device| (device.id == "M1").then_some(80));
+
+        let readings = monitor.poll(&source).unwrap().readings;
+        assert_eq!(readings.len(), 3);
+        assert!(!readings[0].changed);
+        assert!(readings[1].changed);
+        assert!(readings[2].changed);
+
+        source.set_battery(&mouse, 79, false);
+        let readings = monitor.poll(&source).unwrap().readings;
+        assert!(readings[0].changed);
+        assert_eq!(readings[0].status.percentage, 79);
+        assert!(!readings[1].changed);
+        assert!(!readings[2].changed);
+
+        // plugged in
+        source.set_battery(&mouse, 79, true);
+        let readings = monitor.poll(&source).unwrap().readings;
+        assert!(readings[0].changed);
+        assert!(!readings[1].changed);
+
+        source.disconnect(&other_mouse);
+        source.disconnect("0002:0");
+        let readings = monitor.poll(&source).unwrap().readings;
+        assert_eq!(readings.len(), 1);
+    }
+
+    #[test]
+    fn test_poll_failures() {
+        let source = FakeSource::new();
+        let mouse = source.connect(1, None, "Mouse", 80, false);
+        let keyboard = source.connect(2, None, "Keyboard", 50, false);
+        source.set_failing(&mouse, true);
+        let mut monitor = Monitor::new(|_| None);
+
+        let poll = monitor.poll(&source).unwrap();
+        assert_eq!(poll.readings.len(), 1);
+        assert_eq!(poll.readings[0].status.device_id, keyboard);
+        assert_eq!(poll.failures.len(), 1);
+        assert_eq!(poll.failures[0].0.id, mouse);
+        assert!(matches!(
+            poll.failures[0].1,
+            RazermonError::ReadFailed { .. }
+        ));
+    }
+
+    #[test]
+    fn test_low_battery_per_device() {
+        let source = FakeSource::new();
+        let mouse = source.connect(1, None, "Mouse", 9, false);
+        let keyboard = source.connect(2, None, "Keyboard", 50, false);
+        let mut monitor = Monitor::new(|_| None);
+
+        let readings = monitor.poll(&source).unwrap().readings;
+        assert!(readings[0].low_battery);
+        assert!(!readings[1].low_battery);
+
+        source.set_battery(&mouse, 8, false);
+        source.set_battery(&keyboard, 9, false);
+        let readings = monitor.poll(&source).unwrap().readings;
+        assert!(!readings[0].low_battery);
+        assert!(readings[1].low_battery);
+
+        // notifies again at 5%
+        source.set_battery(&mouse, 4, false);
+        let readings = monitor.poll(&source).unwrap().readings;
+        assert!(readings[0].low_battery);
+    }
+}
@@ -0,0 +1,346 @@
+use chrono::{DateTime, Datelike, Duration, Local, TimeZone, Timelike, Utc};
+
+use crate::battery::BatteryData;
+use crate::database::Database;
+use crate::error::Result;
+use crate::heartbeat::{self, Gap};
+
+const HOURS_PER_WEEK: usize = 7 * 24;
+
+/// Hours of the week observed for less than this use the drain rate of the whole week instead
+const MIN_HOUR_SECONDS: f64 = 1800.0;
+
+/// The pattern isn't used for predictions until at least this many hours of the week are known
+const MIN_LEARNED_HOURS: usize = HOURS_PER_WEEK / 2;
+
+/// How far ahead predictions are simulated before giving up
+const MAX_SIMULATED_DAYS: i64 = 90;
+
+/// How fast a device's battery drains at each hour of the week
+///
+/// Unlike the discharge curve, which only looks at the time the device was awake, this counts
+/// the time it was asleep too, so nights and weekends spent idle are part of the prediction.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct WeeklyPattern {
+    /// Seconds spent discharging during each hour of the week, starting on Monday at midnight
+    observed: Vec<f64>,
+    /// Percents lost during each hour of the week
+    drained: Vec<f64>,
+    /// Seconds spent awake over the whole week, the time the percents were lost in
+    awake: f64,
+}
+
+impl WeeklyPattern {
+    /// Learns the pattern from samples ordered oldest first, placing the hours of the week in
+    /// `tz`
+    ///
+    /// Time the device spent asleep, from a 0% sample until the next one, counts as not draining
+    /// at all. The percents lost while awake are spread over the awake time since the previous
+    /// drop. Time spent charging, and time razermon wasn't watching, don't count.
+    pub fn learn<Tz: TimeZone>(entries: &[BatteryData], gaps: &[Gap], tz: &Tz) -> Self {
+        let mut pattern = Self {
+            observed: vec![0.0; HOURS_PER_WEEK],
+            drained: vec![0.0; HOURS_PER_WEEK],
+            awake: 0.0,
+        };
+        let mut level: Option<u8> = None;
+        let mut awake = vec![];
+        let mut last_entry: Option<&BatteryData> = None;
+        for entry in entries {
+            if entry.charging {
+                level = None;
+                awake.clear();
+                last_entry = Some(entry);
+                continue;
+            }
+            if let Some(last) = last_entry.filter(|last| !last.charging) {
+                let hours = hours(last.created_at, entry.created_at, gaps, tz);
+                if last.is_asleep() {
+                    pattern.observe(&hours, 0.0);
+                } else if entry.idle_seconds > 0 {
+                    // compacted samples only tell how long the device was idle, not when
+                    let (idle, active) = split_idle(&hours, entry.idle_seconds);
+                    pattern.observe(&idle, 0.0);
+                    awake.extend(active);
+                } else {
+                    awake.extend(hours);
+                }
+            }
+            if entry.percentage > 0 {
+                match level {
+                    Some(level) if entry.percentage == level => {}
+                    Some(level) if entry.percentage < level => {
+                        pattern.observe(&awake, (level - entry.percentage) as f64);
+                        awake.clear();
+                    }
+                    // started over, or charged while nobody was looking
+                    _ => awake.clear(),
+                }
+                level = Some(entry.percentage);
+            }
+            last_entry = Some(entry);
+        }
+        pattern
+    }
+
+    /// Adds up time spent in each hour of the week, spreading `drained` percents over it
+    fn observe(&mut self, hours: &[(usize, f64)], drained: f64) {
+        let total = hours.iter().map(|(_, seconds)| seconds).sum::<f64>();
+        if total <= 0.0 {
+            return;
+        }
+        for (hour, seconds) in hours {
+            self.observed[*hour] += seconds;
+            self.drained[*hour] += drained * seconds / total;
+        }
+        if drained > 0.0 {
+            self.awake += total;
+        }
+    }
+
+    /// Percents lost per second over the whole week
+    fn overall_rate(&self) -> f64 {
+        let observed = self.observed.iter().sum::<f64>();
+        if observed <= 0.0 {
+            return 0.0;
+        }
+        self.drained.iter().sum::<f64>() / observed
+    }
+
+    /// Percents lost per second during an hour of the week
+    fn rate(&self, hour: usize) -> f64 {
+        if self.observed[hour] < MIN_HOUR_SECONDS {
+            return self.overall_rate();
+        }
+        self.drained[hour] / self.observed[hour]
+    }
+
+    /// Whether enough of the week was observed for predictions to be worth anything
+    pub fn is_learned(&self) -> bool {
+        let learned = self.observed.iter().filter(|s| **s >= MIN_HOUR_SECONDS);
+        learned.count() >= MIN_LEARNED_HOURS && self.overall_rate() > 0.0
+    }
+
+    /// Seconds per percent while awake, over the whole history
+    fn awake_seconds_per_percent(&self) -> f64 {
+        self.awake / self.drained.iter().sum::<f64>()
+    }
+
+    /// Seconds observed, percents lost and seconds awake over the whole week
+    #[cfg(test)]
+    pub fn totals(&self) -> (f64, f64, f64) {
+        let observed = self.observed.iter().sum::<f64>();
+        (observed, self.drained.iter().sum::<f64>(), self.awake)
+    }
+
+    /// How long until the battery drains from `from` down to `to` percent, simulating the drain
+    /// hour by hour starting at `now`, at `seconds_per_percent` while awake
+    ///
+    /// The pattern only tells how the drain is spread over the week, each hour going faster or
+    /// slower than the rest by as much as it usually does, while the estimate sets how fast the
+    /// battery drains overall. That keeps recent changes the estimator picks up in predictions.
+    pub fn time_until<Tz: TimeZone>(
+        &self,
+        from: u8,
+        to: u8,
+        now: DateTime<Utc>,
+        tz: &Tz,
+        seconds_per_percent: f64,
+    ) -> Option<Duration> {
+        if !self.is_learned() || seconds_per_percent <= 0.0 {
+            return None;
+        }
+        let scale = self.awake_seconds_per_percent() / seconds_per_percent;
+        self.simulate(from, to, now, tz, scale)
+    }
+
+    /// Simulates the drain with the rate of every hour multiplied by `scale`
+    fn simulate<Tz: TimeZone>(
+        &self,
+        from: u8,
+        to: u8,
+        now: DateTime<Utc>,
+        tz: &Tz,
+        scale: f64,
+    ) -> Option<Duration> {
+        if !self.is_learned() {
+            return None;
+        }
+        let mut left = from.min(100).saturating_sub(to) as f64;
+        let mut at = now;
+        let end = now + Duration::days(MAX_SIMULATED_DAYS);
+        while left > 0.0 {
+            if at >= end {
+                return None;
+            }
+            let next = next_hour(at);
+            let rate = self.rate(hour_of_week(at, tz)) * scale;
+            let drained = rate * (next - at).num_milliseconds() as f64 / 1000.0;
+            if drained >= left {
+                at += Duration::milliseconds((left / rate * 1000.0).round() as i64);
+                break;
+            }
+            left -= drained;
+            at = next;
+        }
+        Some(at - now)
+    }
+}
+
+/// Hour of the week in `tz`, from 0 on Monday at midnight to 167 on Sunday at 23:00
+fn hour_of_week<Tz: TimeZone>(at: DateTime<Utc>, tz: &Tz) -> usize {
+    let local = at.with_timezone(tz);
+    local.weekday().num_days_from_monday() as usize * 24 + local.hour() as usize
+}
+
+/// Start of the hour after `at`
+pub(crate) fn next_hour(at: DateTime<Utc>) -> DateTime<Utc> {
+    let hours = at.timestamp().div_euclid(3600);
+    Utc.timestamp_opt((hours + 1) * 3600, 0).unwrap()
+}
+
+/// Seconds spent in each hour of the week, by hour of the week
+type Hours = Vec<(usize, f64)>;
+
+/// Splits the time between `from` and `to` into the hours of the week it falls in, leaving out
+/// the time razermon wasn't watching
+fn hours<Tz: TimeZone>(from: DateTime<Utc>, to: DateTime<Utc>, gaps: &[Gap], tz: &Tz) -> Hours {
+    let mut hours = vec![];
+    let mut at = from;
+    while at < to {
+        let next = next_hour(at).min(to);
+        let observed = next - at - heartbeat::unobserved(gaps, at, next);
+        hours.push((
+            hour_of_week(at, tz),
+            observed.num_milliseconds() as f64 / 1000.0,
+        ));
+        at = next;
+    }
+    hours
+}
+
+/// Splits hours into the part spent idle and the part spent awake, spreading `idle_seconds`
+/// evenly over them
+fn split_idle(hours: &[(usize, f64)], idle_seconds: i64) -> (Hours, Hours) {
+    let total = hours.iter().map(|(_, seconds)| seconds).sum::<f64>();
+    if total <= 0.0 {
+        return (vec![], hours.to_vec());
+    }
+    let share = (idle_seconds as f64 / total).min(1.0);
+    let idle = hours.iter().map(|(hour, s)| (*hour, s * share)).collect();
+    let awake = hours
+        .iter()
+        .map(|(hour, s)| (*hour, s * (1.0 - share)))
+        .collect();
+    (idle, awake)
+}
+
+/// The pattern of a device, in the local timezone
+pub(crate) fn load(db: &Database, device_id: &str) -> Result<WeeklyPattern> {
+    let entries = BatteryData::get(db, device_id)?;
+    Ok(WeeklyPattern::learn(
+        &entries,
+        &heartbeat::gaps(db)?,
+        &Local,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(created_at: &str) -> DateTime<Utc> {
+        BatteryData::new(1, created_at, 0, false).created_at
+    }
+
+    /// Two weeks of a mouse used from 9:00 to 17:00 on weekdays, losing 1% an hour, and asleep
+    /// the rest of the time
+    fn work_weeks() -> Vec<BatteryData> {
+        let mut entries = vec![];
+        // 2022-01-03 is a Monday
+        for day in (3..=14).filter(|day| *day != 8 && *day != 9) {
+            for hour in 9..=17 {
+                let created_at = format!("2022-01-{:02} {:02}:00:00", day, hour);
+                entries.push(BatteryData::new(1, &created_at, 99 - hour, false));
+            }
+            let created_at = format!("2022-01-{:02} 17:05:00", day);
+            entries.push(BatteryData::new(1, &created_at, 0, false));
+        }
+        entries
+    }
+
+    #[test]
+    fn test_hour_of_week() {
+        assert_eq!(hour_of_week(at("2022-01-03 00:30:00"), &Utc), 0);
+        assert_eq!(hour_of_week(at("2022-01-04 09:00:00"), &Utc), 33);
+        assert_eq!(hour_of_week(at("2022-01-09 23:59:59"), &Utc), 167);
+        assert_eq!(
+            next_hour(at("2022-01-03 00:30:00")),
+            at("2022-01-03 01:00:00")
+        );
+        assert_eq!(
+            next_hour(at("2022-01-03 01:00:00")),
+            at("2022-01-03 02:00:00")
+        );
+    }
+
+    #[test]
+    fn test_weekend_is_accounted_for() {
+        let pattern = WeeklyPattern::learn(&work_weeks(), &[], &Utc);
+        assert!(pattern.is_learned());
+
+        // friday evening, lasts until tuesday morning
+        let remaining = pattern.time_until(10, 0, at("2022-01-14 17:00:00"), &Utc, 3600.0);
+        assert_eq!(remaining, Some(Duration::hours(3 * 24 + 18)));
+        // monday morning
+        let remaining = pattern.time_until(3, 0, at("2022-01-17 09:00:00"), &Utc, 3600.0);
+        assert_eq!(remaining, Some(Duration::hours(3)));
+        let remaining = pattern.time_until(3, 1, at("2022-01-17 09:30:00"), &Utc, 3600.0);
+        assert_eq!(remaining, Some(Duration::hours(2)));
+        assert_eq!(
+            pattern.time_until(0, 0, at("2022-01-17 09:30:00"), &Utc, 3600.0),
+            Some(Duration::zero())
+        );
+    }
+
+    #[test]
+    fn test_estimated_rate() {
+        let pattern = WeeklyPattern::learn(&work_weeks(), &[], &Utc);
+        assert_eq!(pattern.awake_seconds_per_percent(), 3600.0);
+
+        let friday = at("2022-01-14 17:00:00");
+        // draining twice as fast lately, so it's empty by monday at 14:00 instead of tuesday
+        let remaining = pattern.time_until(10, 0, friday, &Utc, 1800.0);
+        assert_eq!(remaining, Some(Duration::hours(2 * 24 + 21)));
+        assert_eq!(pattern.time_until(10, 0, friday, &Utc, 0.0), None);
+    }
+
+    #[test]
+    fn test_not_learned() {
+        // a single day isn't enough
+        let pattern = WeeklyPattern::learn(&work_weeks()[..10], &[], &Utc);
+        assert!(!pattern.is_learned());
+        assert_eq!(
+            pattern.time_until(10, 0, at("2022-01-14 17:00:00"), &Utc, 3600.0),
+            None
+        );
+    }
+
+    #[test]
+    fn test_charging_and_gaps_are_left_out() {
+        let entries = vec![
+            BatteryData::new(1, "2022-01-03 09:00:00", 80, false),
+            BatteryData::new(1, "2022-01-03 10:00:00", 80, true),
+            BatteryData::new(1, "2022-01-03 12:00:00", 90, true),
+            BatteryData::new(1, "2022-01-03 12:00:00", 90, false),
+            BatteryData::new(1, "2022-01-03 14:00:00", 88, false),
+        ];
+        let gaps = [Gap {
+            start: at("2022-01-03 12:00:00"),
+            end: at("2022-01-03 13:00:00"),
+        }];
+        let pattern = WeeklyPattern::learn(&entries, &gaps, &Utc);
+        assert_eq!(pattern.observed.iter().sum::<f64>(), 3600.0);
+        assert_eq!(pattern.drained[13], 2.0);
+    }
+}
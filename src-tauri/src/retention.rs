@@ -0,0 +1,341 @@
+use std::{
+    sync::atomic::Ordering,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::battery::BatteryData;
+use crate::database::Database;
+use crate::error::{RazermonError, Result};
+use crate::heartbeat;
+use crate::settings;
+use crate::watcher::StopHandle;
+
+/// How many days of raw samples are kept when the `retention_days` setting isn't set
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+/// How often old samples are compacted
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How long the maintenance thread sleeps between checks for being stopped
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Minimum number of days between two vacuums of the database file
+const VACUUM_INTERVAL_DAYS: i64 = 7;
+
+/// How the battery history is kept as it ages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RetentionPolicy {
+    /// Samples younger than this are kept untouched
+    pub raw_days: i64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            raw_days: DEFAULT_RETENTION_DAYS,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Reads the policy from the `retention_days` setting
+    pub fn load(db: &Database) -> Result<Self> {
+        let Some(value) = settings::get(db, "retention_days")? else {
+            return Ok(Self::default());
+        };
+        match value.parse::<i64>() {
+            Ok(raw_days) if raw_days > 0 => Ok(Self { raw_days }),
+            _ => Err(RazermonError::Parse(format!("retention_days {:?}", value))),
+        }
+    }
+}
+
+/// What one maintenance run did
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Maintenance {
+    /// Samples removed by compaction
+    pub compacted: usize,
+    pub vacuumed: bool,
+}
+
+/// Compacts samples older than the retention period and vacuums the database when it's due
+pub(crate) fn maintain(db: &Database, now: DateTime<Utc>) -> Result<Maintenance> {
+    let policy = RetentionPolicy::load(db)?;
+    let compacted = compact(db, now - chrono::Duration::days(policy.raw_days))?;
+
+    let last_vacuum = settings::get(db, "last_vacuum")?
+        .and_then(|millis| millis.parse::<i64>().ok())
+        .and_then(|millis| Utc.timestamp_millis_opt(millis).single());
+    let vacuumed = match last_vacuum {
+        Some(last_vacuum) => now - last_vacuum >= chrono::Duration::days(VACUUM_INTERVAL_DAYS),
+        None => true,
+    };
+    if vacuumed {
+        db.with(|conn| Ok(conn.execute_batch("VACUUM")?))?;
+        settings::set(db, "last_vacuum", &now.timestamp_millis().to_string())?;
+    }
+
+    Ok(Maintenance {
+        compacted,
+        vacuumed,
+    })
+}
+
+/// Reduces samples recorded before `cutoff` to one transition record per percentage
+///
+/// While discharging, the device going to sleep and waking up again at the same percentage adds
+/// two samples each time. Those are removed, and the time asleep, from each 0% sample until the
+/// next one, is folded into the `idle_seconds` of the sample where the percentage dropped along
+/// with the wake-ups. `BatteryData::measurements`, sessions, usage stats and the weekly pattern
+/// read it back as the same time asleep, and the history chart keeps every percentage change.
+/// Runs that aren't ended by a drop before `cutoff` are left alone.
+pub(crate) fn compact(db: &Database, cutoff: DateTime<Utc>) -> Result<usize> {
+    let gaps = heartbeat::gaps(db)?;
+    let mut compacted = 0;
+    for device_id in devices_before(db, cutoff)? {
+        let rows = rows_before(db, &device_id, cutoff)?;
+        let mut start = 0;
+        while start < rows.len() {
+            let level = &rows[start].1;
+            if level.charging || level.percentage == 0 {
+                start += 1;
+                continue;
+            }
+            let end = match rows[start + 1..].iter().position(|(_, sample)| {
+                sample.charging || (sample.percentage != 0 && sample.percentage != level.percentage)
+            }) {
+                Some(offset) => start + 1 + offset,
+                None => break,
+            };
+            let drop = &rows[end].1;
+            if end == start + 1 || drop.charging || drop.percentage > level.percentage {
+                start = end;
+                continue;
+            }
+
+            let asleep = rows[start..=end]
+                .windows(2)
+                .filter(|pair| pair[0].1.is_asleep())
+                .collect::<Vec<_>>();
+            let idle_seconds = asleep
+                .iter()
+                .map(|pair| {
+                    let (from, to) = (pair[0].1.created_at, pair[1].1.created_at);
+                    (to - from - heartbeat::unobserved(&gaps, from, to)).num_seconds()
+                })
+                .sum::<i64>();
+            let wake_ups = asleep
+                .iter()
+                .filter(|pair| pair[1].1.percentage > 0)
+                .count() as u32;
+            let removed = rows[start + 1..end].iter().map(|(id, _)| *id);
+            compacted += fold(db, removed.collect(), rows[end].0, idle_seconds, wake_ups)?;
+            start = end;
+        }
+    }
+    Ok(compacted)
+}
+
+fn devices_before(db: &Database, cutoff: DateTime<Utc>) -> Result<Vec<String>> {
+    db.with(|conn| {
+        let devices = conn
+            .prepare_cached("SELECT DISTINCT device_id FROM battery WHERE created_at < ?1")?
+            .query_map([cutoff.timestamp_millis()], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(devices)
+    })
+}
+
+/// Samples of a device recorded before `cutoff`, oldest first, along with their row ids
+fn rows_before(
+    db: &Database,
+    device_id: &str,
+    cutoff: DateTime<Utc>,
+) -> Result<Vec<(i64, BatteryData)>> {
+    db.with(|conn| {
+        let mut statement = conn.prepare_cached(
+            "SELECT * FROM battery WHERE device_id = ?1 AND created_at < ?2 ORDER BY created_at, id",
+        )?;
+        let mut rows = statement.query((device_id, cutoff.timestamp_millis()))?;
+        let mut res = vec![];
+        while let Some(row) = rows.next()? {
+            res.push((row.get("id")?, serde_rusqlite::from_row::<BatteryData>(row)?));
+        }
+        Ok(res)
+    })
+}
+
+/// Removes samples, adding the idle time and wake-ups they measured to the sample after them
+fn fold(
+    db: &Database,
+    removed: Vec<i64>,
+    into: i64,
+    idle_seconds: i64,
+    wake_ups: u32,
+) -> Result<usize> {
+    db.with(|conn| {
+        let tx = conn.transaction()?;
+        {
+            let mut delete = tx.prepare_cached("DELETE FROM battery WHERE id = ?1")?;
+            for id in &removed {
+                delete.execute([id])?;
+            }
+            tx.prepare_cached(
+                "UPDATE battery SET idle_seconds = idle_seconds + ?1, wake_ups = wake_ups + ?2 WHERE id = ?3",
+            )?
+            .execute((idle_seconds, wake_ups, into))?;
+        }
+        tx.commit()?;
+        Ok(removed.len())
+    })
+}
+
+/// Runs [`maintain`] at startup and every few hours after that
+pub(crate) fn spawn(db: Database) -> StopHandle {
+    let (handle, stopped) = StopHandle::new();
+
+    thread::spawn(move || {
+        let mut last_run: Option<Instant> = None;
+        while !stopped.load(Ordering::Relaxed) {
+            let due = match last_run {
+                Some(last_run) => last_run.elapsed() >= MAINTENANCE_INTERVAL,
+                None => true,
+            };
+            if due {
+                last_run = Some(Instant::now());
+                if let Err(err) = maintain(&db, Utc::now()) {
+                    eprintln!("WARN: Couldn't compact the battery history: {}", err);
+                }
+            }
+            thread::sleep(CHECK_INTERVAL);
+        }
+    });
+
+    handle
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::{BatteryData, BatteryStatus};
+    use crate::pattern::WeeklyPattern;
+    use crate::stats::{self, UsageRange};
+
+    fn save(db: &Database, device_id: &str, created_at: &str, percentage: u8, charging: bool) {
+        let status = BatteryStatus {
+            device_id: device_id.to_string(),
+            product_id: 1,
+            name: "Mouse".to_string(),
+            percentage,
+            charging,
+        };
+        let created_at = BatteryData::new(1, created_at, percentage, charging).created_at;
+        status.save_at(db, created_at).unwrap();
+    }
+
+    fn at(created_at: &str) -> DateTime<Utc> {
+        BatteryData::new(1, created_at, 0, false).created_at
+    }
+
+    /// Samples the monitor writes as they change: a percentage each time it drops, a 0% each
+    /// time the device falls asleep and the percentage again when it wakes up
+    fn save_discharge(db: &Database) {
+        save(db, "M1", "2022-01-01 20:00:00", 76, false);
+        save(db, "M1", "2022-01-01 20:30:00", 0, false);
+        save(db, "M1", "2022-01-01 21:30:00", 76, false);
+        save(db, "M1", "2022-01-01 21:40:00", 0, false);
+        save(db, "M1", "2022-01-01 21:50:00", 76, false);
+        save(db, "M1", "2022-01-01 22:00:00", 75, false);
+        save(db, "M1", "2022-01-01 22:20:00", 0, false);
+        // woke up a percent lower
+        save(db, "M1", "2022-01-01 23:00:00", 74, false);
+        save(db, "M1", "2022-01-01 23:10:00", 0, false);
+        save(db, "M1", "2022-01-01 23:20:00", 74, true);
+    }
+
+    #[test]
+    fn test_compact_folds_sleep_into_transitions() {
+        let db = Database::open_in_memory().unwrap();
+        save_discharge(&db);
+        // still asleep, nothing to fold into yet
+        save(&db, "M2", "2022-01-01 20:05:00", 50, false);
+        save(&db, "M2", "2022-01-01 20:15:00", 0, false);
+        let before = BatteryData::get(&db, "M1").unwrap();
+
+        assert_eq!(compact(&db, at("2022-01-02 00:00:00")).unwrap(), 5);
+        let after = BatteryData::get(&db, "M1").unwrap();
+        let percentages = after.iter().map(|d| d.percentage).collect::<Vec<_>>();
+        assert_eq!(percentages, vec![76, 75, 74, 0, 74]);
+        let idle = after.iter().map(|d| d.idle_seconds).collect::<Vec<_>>();
+        assert_eq!(idle, vec![0, 70 * 60, 40 * 60, 0, 0]);
+        let wake_ups = after.iter().map(|d| d.wake_ups).collect::<Vec<_>>();
+        assert_eq!(wake_ups, vec![0, 2, 1, 0, 0]);
+        assert_eq!(BatteryData::get(&db, "M2").unwrap().len(), 2);
+
+        assert_eq!(
+            BatteryData::measurements(&after, &[]),
+            BatteryData::measurements(&before, &[])
+        );
+        let idle_seconds = |samples: &[BatteryData]| {
+            let sessions = crate::sessions::split(samples, &[]);
+            sessions.iter().map(|s| s.idle_seconds).collect::<Vec<_>>()
+        };
+        assert_eq!(idle_seconds(&after), idle_seconds(&before));
+        assert_eq!(
+            stats::aggregate(&after, &[], UsageRange::Daily, &Utc),
+            stats::aggregate(&before, &[], UsageRange::Daily, &Utc)
+        );
+        // the compacted idle time is spread over the hours it falls in, so only the totals match
+        let (observed, drained, awake) = WeeklyPattern::learn(&after, &[], &Utc).totals();
+        let expected = WeeklyPattern::learn(&before, &[], &Utc).totals();
+        assert_eq!(observed, expected.0);
+        assert!((drained - expected.1).abs() < 1e-9);
+        assert_eq!(awake, expected.2);
+
+        // already compacted
+        assert_eq!(compact(&db, at("2022-01-02 00:00:00")).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_compact_keeps_recent_samples() {
+        let db = Database::open_in_memory().unwrap();
+        save_discharge(&db);
+
+        // the drop ending the first run comes after the cutoff
+        assert_eq!(compact(&db, at("2022-01-01 21:55:00")).unwrap(), 0);
+        assert_eq!(compact(&db, at("2022-01-01 22:10:00")).unwrap(), 4);
+        assert_eq!(BatteryData::get(&db, "M1").unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_maintain() {
+        let db = Database::open_in_memory().unwrap();
+        save_discharge(&db);
+
+        // within the default retention period
+        let maintenance = maintain(&db, at("2022-01-15 00:00:00")).unwrap();
+        assert_eq!(
+            maintenance,
+            Maintenance {
+                compacted: 0,
+                vacuumed: true
+            }
+        );
+
+        settings::set(&db, "retention_days", "7").unwrap();
+        let maintenance = maintain(&db, at("2022-01-16 00:00:00")).unwrap();
+        assert_eq!(
+            maintenance,
+            Maintenance {
+                compacted: 5,
+                vacuumed: false
+            }
+        );
+
+        settings::set(&db, "retention_days", "forever").unwrap();
+        assert!(maintain(&db, at("2022-01-23 00:00:00")).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+
+use chrono::{Datelike, Duration, Local, NaiveDateTime, NaiveTime, Weekday};
+
+use crate::battery::BatteryStatus;
+use crate::database::Database;
+use crate::error::{RazermonError, Result};
+use crate::settings;
+
+/// When the devices are expected to be in use, read from the `work_hours` setting, like
+/// "09:00-18:00", and the `work_days` setting, like "mon,tue,wed,thu,fri"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct WorkHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub days: Vec<Weekday>,
+}
+
+impl Default for WorkHours {
+    fn default() -> Self {
+        Self {
+            start: NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+            days: vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+        }
+    }
+}
+
+/// Whether the battery makes it to the end of today's work hours
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Forecast {
+    LastsThroughToday,
+    /// The battery is expected to run out at this time
+    ChargeBefore(NaiveTime),
+}
+
+impl fmt::Display for Forecast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Forecast::LastsThroughToday => write!(f, "Will last through today"),
+            Forecast::ChargeBefore(time) => write!(f, "Charge before {}", time.format("%H:%M")),
+        }
+    }
+}
+
+impl WorkHours {
+    pub fn load(db: &Database) -> Result<Self> {
+        let mut hours = Self::default();
+        if let Some(value) = settings::get(db, "work_hours")? {
+            let invalid = || RazermonError::Parse(format!("work_hours {:?}", value));
+            let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+            let time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M");
+            hours.start = time(start).map_err(|_| invalid())?;
+            hours.end = time(end).map_err(|_| invalid())?;
+            if hours.end <= hours.start {
+                return Err(invalid());
+            }
+        }
+        if let Some(value) = settings::get(db, "work_days")? {
+            hours.days = value
+                .split(',')
+                .map(|day| day.trim().parse::<Weekday>())
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| RazermonError::Parse(format!("work_days {:?}", value)))?;
+        }
+        Ok(hours)
+    }
+
+    /// Whether `now` is on a work day, before the end of the work hours
+    pub fn is_ahead(&self, now: NaiveDateTime) -> bool {
+        self.days.contains(&now.weekday()) && now.time() < self.end
+    }
+
+    /// Whether a battery expected to last `remaining` from `now` makes it through today's work
+    /// hours, `None` when there are no work hours left today
+    pub fn forecast(&self, now: NaiveDateTime, remaining: Duration) -> Option<Forecast> {
+        if !self.is_ahead(now) {
+            return None;
+        }
+        let empty_at = now + remaining;
+        if empty_at >= now.date().and_time(self.end) {
+            Some(Forecast::LastsThroughToday)
+        } else {
+            Some(Forecast::ChargeBefore(empty_at.time()))
+        }
+    }
+}
+
+/// Today's forecast for a discharging device, from the current estimate of how long it lasts
+pub(crate) fn today(db: &Database, status: &BatteryStatus) -> Result<Option<Forecast>> {
+    forecast_at(db, status, Local::now().naive_local())
+}
+
+fn forecast_at(
+    db: &Database,
+    status: &BatteryStatus,
+    now: NaiveDateTime,
+) -> Result<Option<Forecast>> {
+    let hours = WorkHours::load(db)?;
+    if status.charging || !hours.is_ahead(now) {
+        return Ok(None);
+    }
+    let Some(prediction) = status.remaining(db)? else {
+        return Ok(None);
+    };
+    Ok(hours.forecast(now, prediction.expected()))
+}
+
+/// The forecast to warn about when the device won't make it through the day
+///
+/// Only the first check of each work day counts, usually in the morning when the computer is
+/// turned on, so the warning shows up at most once a day.
+pub(crate) fn morning_warning(
+    db: &Database,
+    status: &BatteryStatus,
+    now: NaiveDateTime,
+) -> Result<Option<Forecast>> {
+    let key = format!("forecast_checked_{}", status.device_id);
+    let today = now.date().format("%Y-%m-%d").to_string();
+    if settings::get(db, &key)?.as_deref() == Some(today.as_str()) {
+        return Ok(None);
+    }
+    if status.charging || !WorkHours::load(db)?.is_ahead(now) {
+        return Ok(None);
+    }
+    settings::set(db, &key, &today)?;
+    match forecast_at(db, status, now)? {
+        Some(forecast @ Forecast::ChargeBefore(_)) => Ok(Some(forecast)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::BatteryData;
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_forecast() {
+        let hours = WorkHours::default();
+        // 2022-01-03 is a Monday
+        let forecast = hours.forecast(at("2022-01-03 08:00:00"), Duration::hours(12));
+        assert_eq!(forecast, Some(Forecast::LastsThroughToday));
+        let forecast = hours.forecast(at("2022-01-03 08:00:00"), Duration::minutes(460));
+        assert_eq!(forecast, Some(Forecast::ChargeBefore(time("15:40"))));
+        assert_eq!(forecast.unwrap().to_string(), "Charge before 15:40");
+        assert_eq!(
+            Forecast::LastsThroughToday.to_string(),
+            "Will last through today"
+        );
+
+        // after hours, and on a weekend
+        let forecast = hours.forecast(at("2022-01-03 18:00:00"), Duration::hours(1));
+        assert_eq!(forecast, None);
+        let forecast = hours.forecast(at("2022-01-08 10:00:00"), Duration::hours(1));
+        assert_eq!(forecast, None);
+    }
+
+    #[test]
+    fn test_load() {
+        let db = Database::open_in_memory().unwrap();
+        assert_eq!(WorkHours::load(&db).unwrap(), WorkHours::default());
+
+        settings::set(&db, "work_hours", "08:30-17:00").unwrap();
+        settings::set(&db, "work_days", "sat, sun").unwrap();
+        let hours = WorkHours::load(&db).unwrap();
+        assert_eq!(hours.start, time("08:30"));
+        assert_eq!(hours.end, time("17:00"));
+        assert_eq!(hours.days, vec![Weekday::Sat, Weekday::Sun]);
+
+        settings::set(&db, "work_days", "someday").unwrap();
+        assert!(WorkHours::load(&db).is_err());
+        settings::set(&db, "work_days", "mon").unwrap();
+        for invalid in ["9 to 5", "17:00-09:00", "09:00-25:00"] {
+            settings::set(&db, "work_hours", invalid).unwrap();
+            assert!(WorkHours::load(&db).is_err());
+        }
+    }
+
+    #[test]
+    fn test_morning_warning() {
+        let db = Database::open_in_memory().unwrap();
+        let mut status = BatteryStatus {
+            device_id: "M1".to_string(),
+            product_id: 1,
+            name: "Mouse".to_string(),
+            percentage: 10,
+            charging: false,
+        };
+        // an hour per percent
+        for (created_at, percentage) in [
+            ("2022-01-01 10:00:00", 12),
+            ("2022-01-01 11:00:00", 11),
+            ("2022-01-01 12:00:00", 10),
+        ] {
+            status.percentage = percentage;
+            let created_at = BatteryData::new(1, created_at, percentage, false).created_at;
+            status.save_at(&db, created_at).unwrap();
+        }
+        status.percentage = 5;
+
+        // not a work day
+        let warning = morning_warning(&db, &status, at("2022-01-02 08:00:00")).unwrap();
+        assert_eq!(warning, None);
+
+        let warning = morning_warning(&db, &status, at("2022-01-03 08:00:00")).unwrap();
+        assert_eq!(warning, Some(Forecast::ChargeBefore(time("13:00"))));
+        // only once a day
+        let warning = morning_warning(&db, &status, at("2022-01-03 09:00:00")).unwrap();
+        assert_eq!(warning, None);
+
+        status.percentage = 10;
+        let warning = morning_warning(&db, &status, at("2022-01-04 08:00:00")).unwrap();
+        assert_eq!(warning, None);
+    }
+}
@@ -0,0 +1,263 @@
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::OptionalExtension;
+
+use crate::battery::BatteryData;
+use crate::database::Database;
+use crate::error::Result;
+use crate::heartbeat::{self, Gap};
+use crate::human_display::HumanDuration;
+
+/// A stretch of time a device spent either discharging or on the charger
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Session {
+    pub device_id: String,
+    /// Whether this is a charge session rather than a discharge one
+    pub charging: bool,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub started_at: DateTime<Utc>,
+    /// When the next session started, or the last sample seen for an ongoing session
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub ended_at: DateTime<Utc>,
+    pub start_percentage: u8,
+    pub end_percentage: u8,
+    /// Time the device spent asleep, reporting 0%
+    pub idle_seconds: i64,
+    /// Average time it took to lose, or gain while charging, 1%
+    pub seconds_per_percent: Option<i64>,
+    /// The session ended because the device was put on the charger
+    pub ended_on_charger: bool,
+    /// The device is still in this session, so it isn't recorded yet
+    #[serde(default)]
+    pub ongoing: bool,
+}
+
+impl Session {
+    /// Builds a session out of consecutive samples sharing the same charging state, `next` being
+    /// the sample that started the following session, if any
+    fn from_samples(samples: &[BatteryData], next: Option<&BatteryData>, gaps: &[Gap]) -> Self {
+        let first = &samples[0];
+        let last = &samples[samples.len() - 1];
+        let mut awake = samples.iter().filter(|s| s.percentage > 0);
+        let start_percentage = awake.clone().next().map_or(0, |s| s.percentage);
+        let end_percentage = awake.next_back().map_or(0, |s| s.percentage);
+
+        // asleep from a 0% sample until the next one, even when that one starts the next session
+        let idle_seconds = samples
+            .iter()
+            .zip(samples[1..].iter().chain(next))
+            .filter(|(from, _)| from.is_asleep())
+            .map(|(from, to)| {
+                let (from, to) = (from.created_at, to.created_at);
+                (to - from - heartbeat::unobserved(gaps, from, to)).num_seconds()
+            })
+            .sum::<i64>()
+            + samples[1..].iter().map(|s| s.idle_seconds).sum::<i64>();
+
+        let seconds_per_percent = if first.charging {
+            let gained = end_percentage as i64 - start_percentage as i64;
+            let unobserved = heartbeat::unobserved(gaps, first.created_at, last.created_at);
+            let active =
+                (last.created_at - first.created_at - unobserved).num_seconds() - idle_seconds;
+            (gained > 0).then(|| active / gained)
+        } else {
+            BatteryData::consumption(samples, gaps)
+        };
+
+        Self {
+            device_id: first.device_id.clone(),
+            charging: first.charging,
+            started_at: first.created_at,
+            ended_at: next.map_or(last.created_at, |n| n.created_at),
+            start_percentage,
+            end_percentage,
+            idle_seconds,
+            seconds_per_percent,
+            ended_on_charger: !first.charging && matches!(next, Some(n) if n.charging),
+            ongoing: next.is_none(),
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.ended_at - self.started_at
+    }
+
+    fn save(&self, db: &Database) -> Result<()> {
+        db.with(|conn| {
+            conn.prepare_cached(
+                "INSERT OR IGNORE INTO sessions (device_id, charging, started_at, ended_at, start_percentage, end_percentage, idle_seconds, seconds_per_percent, ended_on_charger)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?
+            .execute((
+                &self.device_id,
+                self.charging,
+                self.started_at.timestamp_millis(),
+                self.ended_at.timestamp_millis(),
+                self.start_percentage,
+                self.end_percentage,
+                self.idle_seconds,
+                self.seconds_per_percent,
+                self.ended_on_charger,
+            ))?;
+            Ok(())
+        })
+    }
+}
+
+/// Splits samples, oldest first, into sessions wherever the charging state changes
+pub(crate) fn split(samples: &[BatteryData], gaps: &[Gap]) -> Vec<Session> {
+    let mut sessions = vec![];
+    let mut start = 0;
+    for end in 1..=samples.len() {
+        let next = samples.get(end);
+        if matches!(next, Some(n) if n.charging == samples[start].charging) {
+            continue;
+        }
+        sessions.push(Session::from_samples(&samples[start..end], next, gaps));
+        start = end;
+    }
+    sessions
+}
+
+/// Records the sessions of a device that ended since the last recorded one, returning them
+pub(crate) fn record(db: &Database, device_id: &str) -> Result<Vec<Session>> {
+    let mut ended = split(&unrecorded_samples(db, device_id)?, &heartbeat::gaps(db)?);
+    ended.retain(|s| !s.ongoing);
+    for session in &ended {
+        session.save(db)?;
+    }
+    Ok(ended)
+}
+
+/// Every session of a device, newest first, including the ongoing one
+pub(crate) fn history(db: &Database, device_id: &str) -> Result<Vec<Session>> {
+    record(db, device_id)?;
+    let mut sessions = db.with(|conn| {
+        let mut statement = conn.prepare_cached(
+            "SELECT * FROM sessions WHERE device_id = ?1 ORDER BY started_at DESC",
+        )?;
+        let res = serde_rusqlite::from_rows::<Session>(statement.query([device_id])?);
+        let res = res.collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(res)
+    })?;
+    let ongoing = split(&unrecorded_samples(db, device_id)?, &heartbeat::gaps(db)?).pop();
+    sessions.splice(0..0, ongoing);
+    Ok(sessions)
+}
+
+/// How long the battery lasted between the last two charges, like "4d 6h"
+pub(crate) fn last_discharge(db: &Database, device_id: &str) -> Result<Option<String>> {
+    let sessions = history(db, device_id)?;
+    let last = sessions.iter().find(|s| s.ended_on_charger);
+    Ok(last.map(|s| s.duration().as_human().to_string()))
+}
+
+/// Samples from the start of the first session that isn't recorded yet
+fn unrecorded_samples(db: &Database, device_id: &str) -> Result<Vec<BatteryData>> {
+    let last_ended_at = db.with(|conn| {
+        let ended_at: Option<i64> = conn
+            .prepare_cached("SELECT MAX(ended_at) FROM sessions WHERE device_id = ?1")?
+            .query_row([device_id], |row| row.get(0))
+            .optional()?
+            .flatten();
+        Ok(ended_at)
+    })?;
+    match last_ended_at {
+        Some(millis) => BatteryData::get_since(db, device_id, millis),
+        None => BatteryData::get(db, device_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::BatteryStatus;
+
+    fn samples() -> Vec<BatteryData> {
+        vec![
+            BatteryData::new(1, "2022-01-01 10:00:00", 80, false),
+            BatteryData::new(1, "2022-01-01 11:00:00", 79, false),
+            BatteryData::new(1, "2022-01-01 12:00:00", 0, false),
+            BatteryData::new(1, "2022-01-01 14:00:00", 79, false),
+            BatteryData::new(1, "2022-01-01 15:00:00", 78, false),
+            BatteryData::new(1, "2022-01-01 16:00:00", 78, true),
+            BatteryData::new(1, "2022-01-01 16:30:00", 88, true),
+            BatteryData::new(1, "2022-01-01 17:00:00", 98, true),
+            BatteryData::new(1, "2022-01-01 17:10:00", 98, false),
+            BatteryData::new(1, "2022-01-01 18:10:00", 97, false),
+        ]
+    }
+
+    fn save(db: &Database, samples: &[BatteryData]) {
+        for sample in samples {
+            let status = BatteryStatus {
+                device_id: sample.device_id.clone(),
+                product_id: sample.product_id,
+                name: "Mouse".to_string(),
+                percentage: sample.percentage,
+                charging: sample.charging,
+            };
+            status.save_at(db, sample.created_at).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let samples = samples();
+        let sessions = split(&samples, &[]);
+        assert_eq!(sessions.len(), 3);
+
+        let discharge = &sessions[0];
+        assert!(!discharge.charging);
+        assert_eq!(discharge.started_at, samples[0].created_at);
+        assert_eq!(discharge.ended_at, samples[5].created_at);
+        assert_eq!(discharge.start_percentage, 80);
+        assert_eq!(discharge.end_percentage, 78);
+        assert_eq!(discharge.idle_seconds, 7200);
+        assert_eq!(discharge.seconds_per_percent, Some(5400));
+        assert!(discharge.ended_on_charger);
+        assert!(!discharge.ongoing);
+
+        let charge = &sessions[1];
+        assert!(charge.charging);
+        assert_eq!(charge.start_percentage, 78);
+        assert_eq!(charge.end_percentage, 98);
+        assert_eq!(charge.seconds_per_percent, Some(180));
+        assert!(!charge.ended_on_charger);
+        assert!(!charge.ongoing);
+
+        let ongoing = &sessions[2];
+        assert!(!ongoing.charging);
+        assert_eq!(ongoing.ended_at, samples[9].created_at);
+        assert!(ongoing.ongoing);
+
+        assert!(split(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn test_record() {
+        let db = Database::open_in_memory().unwrap();
+        let samples = samples();
+        save(&db, &samples[..7]);
+
+        let recorded = record(&db, "0001:0").unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert!(record(&db, "0001:0").unwrap().is_empty());
+
+        save(&db, &samples[7..]);
+        let recorded = record(&db, "0001:0").unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert!(recorded[0].charging);
+
+        let history = history(&db, "0001:0").unwrap();
+        assert_eq!(history.len(), 3);
+        assert!(history[0].ongoing);
+        assert!(history[1].charging);
+        assert_eq!(history[2], split(&samples, &[])[0]);
+
+        assert_eq!(
+            last_discharge(&db, "0001:0").unwrap(),
+            Some("6h".to_string())
+        );
+        assert_eq!(last_discharge(&db, "M2").unwrap(), None);
+    }
+}
@@ -1,22 +1,39 @@
-use crate::database::Conn;
+use rusqlite::OptionalExtension;
 
-pub fn get(key: &str) -> anyhow::Result<Option<String>> {
-    let db = Conn::new()?;
-    let mut stmt = db
-        .conn
-        .prepare("SELECT value FROM settings WHERE key = ?1")?;
-    let mut rows = stmt.query([key])?;
-    if let Some(row) = rows.next()? {
-        return Ok(Some(row.get(0)?));
-    }
-    Ok(None)
+use crate::database::Database;
+use crate::error::Result;
+
+pub fn get(db: &Database, key: &str) -> Result<Option<String>> {
+    db.with(|conn| {
+        let value = conn
+            .prepare_cached("SELECT value FROM settings WHERE key = ?1")?
+            .query_row([key], |row| row.get(0))
+            .optional()?;
+        Ok(value)
+    })
 }
 
-pub fn set(key: &str, value: &str) -> anyhow::Result<()> {
-    let db = Conn::new()?;
-    db.conn.execute(
+pub fn set(db: &Database, key: &str, value: &str) -> Result<()> {
+    db.with(|conn| {
+        conn.prepare_cached(
             "INSERT INTO settings (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = ?2",
-            (&key, &value),
-        )?;
-    Ok(())
+        )?
+        .execute((&key, &value))?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings() {
+        let db = Database::open_in_memory().unwrap();
+        assert_eq!(get(&db, "device_id").unwrap(), None);
+
+        set(&db, "device_id", "M1").unwrap();
+        set(&db, "device_id", "M2").unwrap();
+        assert_eq!(get(&db, "device_id").unwrap(), Some("M2".to_string()));
+    }
 }
@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Utc};
+
+use crate::battery::BatteryData;
+use crate::database::Database;
+use crate::error::Result;
+use crate::heartbeat::{self, Gap};
+use crate::pattern;
+
+/// How the statistics are grouped
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UsageRange {
+    Daily,
+    /// Weeks starting on Monday
+    Weekly,
+}
+
+impl UsageRange {
+    /// First day of the period `date` falls in
+    fn start(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            UsageRange::Daily => date,
+            UsageRange::Weekly => {
+                date - Duration::days(date.weekday().num_days_from_monday() as i64)
+            }
+        }
+    }
+}
+
+/// How a device was used during a day or a week
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub(crate) struct UsageStats {
+    /// First day of the period
+    pub start: NaiveDate,
+    pub awake_seconds: i64,
+    /// Time spent asleep, reporting 0%
+    pub asleep_seconds: i64,
+    pub wake_ups: u32,
+    /// Percents lost while discharging
+    pub percent_consumed: u32,
+    /// Percents lost per hour awake
+    pub drain_per_hour: Option<f64>,
+}
+
+impl UsageStats {
+    fn new(start: NaiveDate) -> Self {
+        Self {
+            start,
+            awake_seconds: 0,
+            asleep_seconds: 0,
+            wake_ups: 0,
+            percent_consumed: 0,
+            drain_per_hour: None,
+        }
+    }
+}
+
+/// Periods being added up, keyed on their first day
+struct Periods<'a, Tz: TimeZone> {
+    stats: BTreeMap<NaiveDate, UsageStats>,
+    range: UsageRange,
+    tz: &'a Tz,
+}
+
+impl<'a, Tz: TimeZone> Periods<'a, Tz> {
+    fn at(&mut self, at: DateTime<Utc>) -> &mut UsageStats {
+        let start = self
+            .range
+            .start(at.with_timezone(self.tz).naive_local().date());
+        self.stats
+            .entry(start)
+            .or_insert_with(|| UsageStats::new(start))
+    }
+
+    /// Adds the observed time between `from` and `to` to the periods it falls in, hour by hour so
+    /// it's split at midnight, `asleep` being the share of it the device spent asleep
+    fn spend(&mut self, from: DateTime<Utc>, to: DateTime<Utc>, gaps: &[Gap], asleep: f64) {
+        let mut at = from;
+        while at < to {
+            let next = pattern::next_hour(at).min(to);
+            let seconds = (next - at - heartbeat::unobserved(gaps, at, next)).num_seconds();
+            let asleep_seconds = (seconds as f64 * asleep).round() as i64;
+            let stats = self.at(at);
+            stats.asleep_seconds += asleep_seconds;
+            stats.awake_seconds += seconds - asleep_seconds;
+            at = next;
+        }
+    }
+}
+
+/// Aggregates samples, oldest first, into periods in `tz`, oldest first
+///
+/// Time from a sample reporting 0% until the next one counts as asleep, the same way
+/// `WeeklyPattern::learn` counts it, and the rest of the time spent discharging as awake. Time
+/// spent charging, and time razermon wasn't watching, don't count. Compacted samples carry the
+/// time asleep and the wake-ups folded into them.
+pub(crate) fn aggregate<Tz: TimeZone>(
+    entries: &[BatteryData],
+    gaps: &[Gap],
+    range: UsageRange,
+    tz: &Tz,
+) -> Vec<UsageStats> {
+    let mut periods = Periods {
+        stats: BTreeMap::new(),
+        range,
+        tz,
+    };
+    let mut level = entries
+        .first()
+        .filter(|e| !e.charging && e.percentage > 0)
+        .map(|e| e.percentage);
+    for pair in entries.windows(2) {
+        let (last, entry) = (&pair[0], &pair[1]);
+        if entry.charging {
+            level = None;
+            continue;
+        }
+        if !last.charging {
+            let asleep = if last.is_asleep() {
+                1.0
+            } else {
+                idle_share(last, entry, gaps)
+            };
+            periods.spend(last.created_at, entry.created_at, gaps, asleep);
+            let wake_ups = entry.wake_ups + (last.is_asleep() && entry.percentage > 0) as u32;
+            if wake_ups > 0 {
+                periods.at(entry.created_at).wake_ups += wake_ups;
+            }
+        }
+        if entry.percentage > 0 {
+            if let Some(level) = level.filter(|level| entry.percentage < *level) {
+                periods.at(entry.created_at).percent_consumed += (level - entry.percentage) as u32;
+            }
+            level = Some(entry.percentage);
+        }
+    }
+
+    periods
+        .stats
+        .into_values()
+        .map(|mut stats| {
+            if stats.awake_seconds > 0 {
+                let hours = stats.awake_seconds as f64 / 3600.0;
+                stats.drain_per_hour = Some(stats.percent_consumed as f64 / hours);
+            }
+            stats
+        })
+        .collect()
+}
+
+/// Share of the observed time between two samples a compacted sample says was spent idle
+fn idle_share(last: &BatteryData, entry: &BatteryData, gaps: &[Gap]) -> f64 {
+    let (from, to) = (last.created_at, entry.created_at);
+    let observed = (to - from - heartbeat::unobserved(gaps, from, to)).num_seconds();
+    if entry.idle_seconds <= 0 || observed <= 0 {
+        return 0.0;
+    }
+    (entry.idle_seconds as f64 / observed as f64).min(1.0)
+}
+
+/// Usage statistics of a device, in the local timezone
+pub(crate) fn usage(db: &Database, device_id: &str, range: UsageRange) -> Result<Vec<UsageStats>> {
+    let entries = BatteryData::get(db, device_id)?;
+    Ok(aggregate(&entries, &heartbeat::gaps(db)?, range, &Local))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<BatteryData> {
+        vec![
+            // 2022-01-03 is a Monday
+            BatteryData::new(1, "2022-01-03 09:00:00", 80, false),
+            BatteryData::new(1, "2022-01-03 10:00:00", 79, false),
+            BatteryData::new(1, "2022-01-03 11:00:00", 0, false),
+            BatteryData::new(1, "2022-01-03 13:00:00", 79, false),
+            BatteryData::new(1, "2022-01-03 14:00:00", 77, false),
+            BatteryData::new(1, "2022-01-03 23:00:00", 77, false),
+            BatteryData::new(1, "2022-01-04 01:00:00", 76, false),
+            BatteryData::new(1, "2022-01-04 02:00:00", 76, true),
+            BatteryData::new(1, "2022-01-04 03:00:00", 90, true),
+        ]
+    }
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn test_daily() {
+        let stats = aggregate(&entries(), &[], UsageRange::Daily, &Utc);
+        assert_eq!(stats.len(), 2);
+
+        let monday = &stats[0];
+        assert_eq!(monday.start, date("2022-01-03"));
+        assert_eq!(monday.awake_seconds, 13 * 3600);
+        assert_eq!(monday.asleep_seconds, 2 * 3600);
+        assert_eq!(monday.wake_ups, 1);
+        assert_eq!(monday.percent_consumed, 3);
+        assert_eq!(monday.drain_per_hour, Some(3.0 / 13.0));
+
+        // charging doesn't count
+        let tuesday = &stats[1];
+        assert_eq!(tuesday.start, date("2022-01-04"));
+        assert_eq!(tuesday.awake_seconds, 3600);
+        assert_eq!(tuesday.asleep_seconds, 0);
+        assert_eq!(tuesday.percent_consumed, 1);
+    }
+
+    #[test]
+    fn test_weekly() {
+        let gaps = [Gap {
+            start: BatteryData::new(1, "2022-01-03 15:00:00", 0, false).created_at,
+            end: BatteryData::new(1, "2022-01-03 17:00:00", 0, false).created_at,
+        }];
+        let stats = aggregate(&entries(), &gaps, UsageRange::Weekly, &Utc);
+        assert_eq!(
+            stats,
+            vec![UsageStats {
+                start: date("2022-01-03"),
+                awake_seconds: 12 * 3600,
+                asleep_seconds: 2 * 3600,
+                wake_ups: 1,
+                percent_consumed: 4,
+                drain_per_hour: Some(4.0 / 12.0),
+            }]
+        );
+        assert!(aggregate(&[], &[], UsageRange::Weekly, &Utc).is_empty());
+    }
+
+    #[test]
+    fn test_compacted() {
+        let entries = vec![
+            BatteryData::new(1, "2022-01-03 09:00:00", 80, false),
+            BatteryData {
+                idle_seconds: 3 * 3600,
+                wake_ups: 2,
+                ..BatteryData::new(1, "2022-01-03 13:00:00", 79, false)
+            },
+        ];
+        let stats = aggregate(&entries, &[], UsageRange::Daily, &Utc);
+        assert_eq!(stats[0].awake_seconds, 3600);
+        assert_eq!(stats[0].asleep_seconds, 3 * 3600);
+        assert_eq!(stats[0].wake_ups, 2);
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::device::{charge_percentage, DeviceInfo, DeviceSource};
+use crate::error::{RazermonError, Result};
+
+pub const DEFAULT_ROOT: &str = "/sys/bus/hid/drivers";
+
+/// Reads devices exposed by the OpenRazer kernel driver
+///
+/// OpenRazer registers one HID driver per device class (`razermouse`, `razerkbd`, ...) and each
+/// bound device gets a directory named after its HID id, like `0003:1532:007A.0001`, with one
+/// file per attribute.
+pub(crate) struct SysfsSource {
+    root: PathBuf,
+}
+
+impl Default for SysfsSource {
+    fn default() -> Self {
+        Self::new(DEFAULT_ROOT)
+    }
+}
+
+impl SysfsSource {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Whether the OpenRazer driver is loaded and bound to at least one device
+    ///
+    /// The HID drivers directory exists on every Linux system, so this looks for a `razer*`
+    /// driver with a device under it instead.
+    pub fn is_available(&self) -> bool {
+        let Ok(drivers) = fs::read_dir(&self.root) else {
+            return false;
+        };
+        drivers
+            .flatten()
+            .filter(|driver| driver.file_name().to_string_lossy().starts_with("razer"))
+            .filter_map(|driver| fs::read_dir(driver.path()).ok())
+            .flat_map(|entries| entries.flatten())
+            .any(|entry| product_id(&entry.path()).is_some())
+    }
+
+    /// Lists every battery-powered device along with its directory, skipping the HID interfaces
+    /// the driver didn't attach attributes to
+    fn device_dirs(&self) -> Result<Vec<(DeviceInfo, PathBuf)>> {
+        let mut dirs: Vec<(DeviceInfo, PathBuf)> = vec![];
+        let mut physical_devices = vec![];
+        if !self.root.exists() {
+            return Ok(dirs);
+        }
+
+        let read_failed = |err| RazermonError::read_failed(&self.root.to_string_lossy(), err);
+        for driver in fs::read_dir(&self.root).map_err(read_failed)? {
+            let driver = driver.map_err(read_failed)?;
+            if !driver.file_name().to_string_lossy().starts_with("razer") {
+                continue;
+            }
+
+            for entry in fs::read_dir(driver.path()).map_err(read_failed)? {
+                let path = entry.map_err(read_failed)?.path();
+                let Some(product_id) = product_id(&path) else {
+                    continue;
+                };
+                // only devices with a battery, not keyboards or mats
+                if !path.join("device_type").exists() || !path.join("charge_level").exists() {
+                    continue;
+                }
+                // other interfaces of a device already listed
+                let physical_device = physical_device(&path);
+                if physical_devices.contains(&physical_device) {
+                    continue;
+                }
+                let serial = read_attribute(&path, "device_serial").ok();
+                let mut device =
+                    DeviceInfo::new(product_id, serial, &read_attribute(&path, "device_type")?);
+                device.id = match &device.serial {
+                    Some(serial) => serial.clone(),
+                    None => port_id(product_id, &physical_device),
+                };
+                physical_devices.push(physical_device);
+                dirs.push((device, path));
+            }
+        }
+
+        dirs.sort_by(|(_, a), (_, b)| a.file_name().cmp(&b.file_name()));
+        Ok(dirs)
+    }
+
+    fn device_dir(&self, device: &DeviceInfo) -> Result<PathBuf> {
+        match self
+            .device_dirs()?
+            .into_iter()
+            .find(|(d, _)| d.id == device.id)
+        {
+            Some((_, path)) => Ok(path),
+            None => Err(RazermonError::DeviceUnavailable(device.id.clone())),
+        }
+    }
+}
+
+impl DeviceSource for SysfsSource {
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        Ok(self.device_dirs()?.into_iter().map(|(d, _)| d).collect())
+    }
+
+    fn battery_charge(&self, device: &DeviceInfo) -> Result<u8> {
+        let path = self.device_dir(device)?;
+        let raw = read_attribute(&path, "charge_level")?;
+        let raw: u8 = raw
+            .parse()
+            .map_err(|_| RazermonError::Parse(format!("charge_level {:?}", raw)))?;
+        Ok(charge_percentage(raw))
+    }
+
+    fn charging_status(&self, device: &DeviceInfo) -> Result<bool> {
+        let path = self.device_dir(device)?;
+        Ok(read_attribute(&path, "charge_status")? == "1")
+    }
+}
+
+fn read_attribute(path: &Path, name: &str) -> Result<String> {
+    let file = path.join(name);
+    match fs::read_to_string(&file) {
+        Ok(value) => Ok(value.trim().to_string()),
+        Err(err) => Err(RazermonError::read_failed(&file.to_string_lossy(), err)),
+    }
+}
+
+/// Directory of the physical device a HID directory belongs to
+///
+/// Driver directories link to HID devices living under the interface they were found on, like
+/// `.../usb1/1-2/1-2:1.0/0003:1532:007A.0001`, so interfaces of one USB device share the parent
+/// of their interface directory.
+fn physical_device(path: &Path) -> PathBuf {
+    let Ok(path) = fs::canonicalize(path) else {
+        return path.to_path_buf();
+    };
+    let Some(interface) = path.parent() else {
+        return path;
+    };
+    match interface.file_name().and_then(|name| name.to_str()) {
+        Some(name) if is_usb_interface(name) => interface.parent().unwrap_or(interface).into(),
+        _ => interface.into(),
+    }
+}
+
+/// Id of a device without a serial number: the product id plus the port it's plugged into, like
+/// `007a:1-2`, so it doesn't change when other devices come and go
+fn port_id(product_id: u16, physical_device: &Path) -> String {
+    let port = physical_device.file_name().unwrap_or_default();
+    format!("{:04x}:{}", product_id, port.to_string_lossy())
+}
+
+/// Whether a directory name is a USB interface, like `1-2:1.0` for configuration 1, interface 0
+fn is_usb_interface(name: &str) -> bool {
+    let Some((_, suffix)) = name.rsplit_once(':') else {
+        return false;
+    };
+    let Some((configuration, interface)) = suffix.split_once('.') else {
+        return false;
+    };
+    [configuration, interface]
+        .iter()
+        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Extracts the product id from a HID directory name like `0003:1532:007A.0001`
+fn product_id(path: &Path) -> Option<u16> {
+    let name = path.file_name()?.to_str()?;
+    let mut parts = name.split([':', '.']);
+    let _bus = parts.next()?;
+    let _vendor = parts.next()?;
+    u16::from_str_radix(parts.next()?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lays out a HID device the way the kernel does, under the USB interface it was found on,
+    /// and links it from its driver's directory
+    fn write_device(
+        root: &Path,
+        driver: &str,
+        interface: &str,
+        id: &str,
+        attributes: &[(&str, &str)],
+    ) {
+        let path = root.join("devices").join(interface).join(id);
+        fs::create_dir_all(&path).unwrap();
+        for (name, value) in attributes {
+            fs::write(path.join(name), format!("{}\n", value)).unwrap();
+        }
+        fs::create_dir_all(root.join(driver)).unwrap();
+        std::os::unix::fs::symlink(&path, root.join(driver).join(id)).unwrap();
+    }
+
+    #[test]
+    fn test_product_id() {
+        assert_eq!(product_id(Path::new("0003:1532:007A.0001")), Some(0x007a));
+        assert_eq!(product_id(Path::new("module")), None);
+    }
+
+    #[test]
+    fn test_sysfs_devices() {
+        let root = tempfile::tempdir().unwrap();
+        write_device(
+            root.path(),
+            "razermouse",
+            "1-1/1-1:1.0",
+            "0003:1532:007A.0001",
+            &[
+                ("device_type", "Razer Viper Ultimate (Wireless)"),
+                ("device_serial", "PM2012H0001"),
+                ("charge_level", "128"),
+                ("charge_status", "0"),
+            ],
+        );
+        // secondary interface of the same mouse, without attributes
+        write_device(
+            root.path(),
+            "razermouse",
+            "1-1/1-1:1.1",
+            "0003:1532:007A.0002",
+            &[],
+        );
+        write_device(
+            root.path(),
+            "razermouse",
+            "1-2/1-2:1.0",
+            "0003:1532:007A.0005",
+            &[
+                ("device_type", "Razer Viper Ultimate (Wireless)"),
+                ("device_serial", "PM2012H0002"),
+                ("charge_level", "255"),
+                ("charge_status", "1"),
+            ],
+        );
+        write_device(
+            root.path(),
+            "razerkbd",
+            "1-3/1-3:1.0",
+            "0003:1532:0226.0003",
+            &[("device_type", "Razer Huntsman Elite")],
+        );
+        write_device(
+            root.path(),
+            "usbhid",
+            "1-4/1-4:1.0",
+            "0003:046D:C52B.0004",
+            &[("device_type", "Not a Razer")],
+        );
+
+        let source = SysfsSource::new(root.path());
+        let devices = source.devices().unwrap();
+        // the keyboard has no battery
+        assert_eq!(devices.len(), 2);
+
+        let mouse = source.identify("PM2012H0001").unwrap().unwrap();
+        assert_eq!(mouse.product_id, 0x007a);
+        assert_eq!(mouse.name, "Razer Viper Ultimate (Wireless)");
+        assert_eq!(source.battery_charge(&mouse).unwrap(), 50);
+        assert!(!source.charging_status(&mouse).unwrap());
+
+        let other_mouse = source.identify("PM2012H0002").unwrap().unwrap();
+        assert_eq!(source.battery_charge(&other_mouse).unwrap(), 100);
+        assert!(source.charging_status(&other_mouse).unwrap());
+
+        assert!(devices.iter().all(|d| d.product_id != 0x0226));
+    }
+
+    #[test]
+    fn test_identical_devices_without_serial() {
+        let root = tempfile::tempdir().unwrap();
+        // two dongles of the same model, each with two interfaces the driver attached to
+        for (port, first_id) in [("1-1", 1), ("1-2", 3)] {
+            for interface in 0..2 {
+                write_device(
+                    root.path(),
+                    "razermouse",
+                    &format!("{}/{}:1.{}", port, port, interface),
+                    &format!("0003:1532:007A.{:04X}", first_id + interface),
+                    &[
+                        ("device_type", "Razer Viper Ultimate (Wireless)"),
+                        ("charge_level", "255"),
+                    ],
+                );
+            }
+        }
+
+        let source = SysfsSource::new(root.path());
+        let ids = || {
+            let devices = source.devices().unwrap();
+            devices.into_iter().map(|d| d.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(), vec!["007a:1-1", "007a:1-2"]);
+
+        // unplugging the first dongle leaves the second one's id alone
+        for id in ["0003:1532:007A.0001", "0003:1532:007A.0002"] {
+            fs::remove_file(root.path().join("razermouse").join(id)).unwrap();
+        }
+        assert_eq!(ids(), vec!["007a:1-2"]);
+    }
+
+    #[test]
+    fn test_is_usb_interface() {
+        assert!(is_usb_interface("1-2:1.0"));
+        assert!(is_usb_interface("3-1.4:1.2"));
+        assert!(!is_usb_interface("1-2"));
+        assert!(!is_usb_interface("hci0:256"));
+    }
+
+    #[test]
+    fn test_missing_root() {
+        let source = SysfsSource::new("/nonexistent/razermon");
+        assert!(source.devices().unwrap().is_empty());
+        assert!(!source.is_available());
+    }
+
+    #[test]
+    fn test_is_available() {
+        let root = tempfile::tempdir().unwrap();
+        let source = SysfsSource::new(root.path());
+        // generic HID drivers are always there
+        write_device(
+            root.path(),
+            "hid-generic",
+            "1-1/1-1:1.0",
+            "0003:046D:C52B.0001",
+            &[],
+        );
+        assert!(!source.is_available());
+
+        // driver loaded, nothing bound to it
+        fs::create_dir_all(root.path().join("razermouse").join("module")).unwrap();
+        assert!(!source.is_available());
+
+        write_device(
+            root.path(),
+            "razermouse",
+            "1-2/1-2:1.0",
+            "0003:1532:007A.0002",
+            &[],
+        );
+        assert!(source.is_available());
+    }
+}
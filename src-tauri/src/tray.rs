@@ -0,0 +1,242 @@
+use tauri::{AppHandle, CustomMenuItem, SystemTrayMenu, SystemTrayMenuItem};
+
+use crate::battery::BatteryStatus;
+use crate::database::Database;
+use crate::device::{DeviceInfo, DeviceSource};
+use crate::error::{RazermonError, Result};
+use crate::human_display::HumanDuration;
+use crate::schedule;
+use crate::settings;
+
+const NO_DEVICES: &str = "No devices found";
+
+/// A connected device along with what could be read from it
+pub(crate) struct TrayDevice {
+    pub info: DeviceInfo,
+    pub status: Result<BatteryStatus>,
+}
+
+/// Reads every connected device, returning the tray title and a menu built around the selected
+/// device
+pub(crate) fn build(
+    db: &Database,
+    source: &dyn DeviceSource,
+    selected: Option<&str>,
+) -> (String, SystemTrayMenu) {
+    let devices = match source.devices() {
+        Ok(devices) => devices,
+        Err(err) => {
+            eprintln!("Error scanning for devices: {}", err);
+            let remaining = format!("⚠️ {}", err);
+            return ("⚠️".to_string(), menu(&[], None, &remaining, None));
+        }
+    };
+    let devices = devices
+        .into_iter()
+        .map(|info| TrayDevice {
+            status: BatteryStatus::read(source, &info),
+            info,
+        })
+        .collect::<Vec<_>>();
+
+    let selected = selected.and_then(|id| devices.iter().find(|d| d.info.id == id));
+    let (title, remaining) = match selected.map(|d| &d.status) {
+        Some(Ok(status)) => (status.to_string(), remaining_title(db, status)),
+        Some(Err(err)) => ("⚠️".to_string(), format!("⚠️ {}", err)),
+        None if devices.is_empty() => (NO_DEVICES.to_string(), NO_DEVICES.to_string()),
+        None => ("".to_string(), remaining_title_missing()),
+    };
+    let forecast = match selected.map(|d| &d.status) {
+        Some(Ok(status)) => forecast_title(db, status),
+        _ => None,
+    };
+    let selected = selected.map(|d| d.info.id.as_str());
+    (
+        title,
+        menu(&devices, selected, &remaining, forecast.as_deref()),
+    )
+}
+
+/// Regenerates the tray from the current device set and selection
+pub(crate) fn refresh(handle: &AppHandle, db: &Database, source: &dyn DeviceSource) {
+    let selected = settings::get(db, "device_id").unwrap_or_else(|err| {
+        eprintln!("WARN: Couldn't load the selected device: {}", err);
+        None
+    });
+    let (title, menu) = build(db, source, selected.as_deref());
+    let tray = handle.tray_handle();
+    if let Err(err) = tray.set_menu(menu) {
+        eprintln!("WARN: Couldn't update the tray menu: {}", err);
+    }
+    if let Err(err) = tray.set_title(&title) {
+        eprintln!("WARN: Couldn't update the tray title: {}", err);
+    }
+}
+
+/// Shows the failure in the tray, keeping the title short
+pub(crate) fn show_error(handle: &AppHandle, err: &RazermonError) {
+    let message = format!("⚠️ {}", err);
+    let tray = handle.tray_handle();
+    if let Err(err) = tray.set_title("⚠️") {
+        eprintln!("WARN: Couldn't update the tray title: {}", err);
+    }
+    if let Err(err) = tray.get_item("remaining").set_title(message) {
+        eprintln!("WARN: Couldn't update the tray menu: {}", err);
+    }
+}
+
+/// How long the device's battery is expected to last, like "3h 20m remaining", or how long
+/// until it's full while charging, like "full in 38m"
+pub(crate) fn remaining(db: &Database, status: &BatteryStatus) -> Result<Option<String>> {
+    if status.charging {
+        if status.percentage >= 100 {
+            return Ok(Some("Fully charged".to_string()));
+        }
+        let full = status.time_to_full(db)?;
+        return Ok(full.map(|d| format!("full in {}", d.as_human())));
+    }
+    let remaining = status.fmt_remaining(db)?;
+    Ok(remaining.map(|r| format!("{} remaining", r)))
+}
+
+fn remaining_title(db: &Database, status: &BatteryStatus) -> String {
+    match remaining(db, status) {
+        Ok(Some(remaining)) => remaining,
+        Ok(None) if status.charging => "Not enough data to calculate charging time yet".to_string(),
+        Ok(None) => remaining_title_missing(),
+        Err(err) => format!("⚠️ {}", err),
+    }
+}
+
+/// Whether the device makes it through the work day, like "Charge before 15:40"
+fn forecast_title(db: &Database, status: &BatteryStatus) -> Option<String> {
+    match schedule::today(db, status) {
+        Ok(forecast) => forecast.map(|f| f.to_string()),
+        Err(err) => Some(format!("⚠️ {}", err)),
+    }
+}
+
+fn remaining_title_missing() -> String {
+    "Not enough data to calulate ETA yet".to_string()
+}
+
+fn menu(
+    devices: &[TrayDevice],
+    selected: Option<&str>,
+    remaining: &str,
+    forecast: Option<&str>,
+) -> SystemTrayMenu {
+    let mut remaining_item = CustomMenuItem::new("remaining", remaining);
+    remaining_item.enabled = false;
+    let mut menu = SystemTrayMenu::new().add_item(remaining_item);
+    if let Some(forecast) = forecast {
+        let mut forecast_item = CustomMenuItem::new("forecast", forecast);
+        forecast_item.enabled = false;
+        menu = menu.add_item(forecast_item);
+    }
+    menu = menu.add_native_item(SystemTrayMenuItem::Separator);
+
+    if !devices.is_empty() {
+        menu = menu
+            .add_item(CustomMenuItem::new("usage", "Usage Chart..."))
+            .add_item(CustomMenuItem::new("notify", "Test Notification"))
+            .add_native_item(SystemTrayMenuItem::Separator);
+
+        for device in devices {
+            let mut item = CustomMenuItem::new(
+                format!("device_{}", device.info.id),
+                device_title(device, devices),
+            );
+            item.selected = selected == Some(device.info.id.as_str());
+            menu = menu.add_item(item);
+        }
+
+        menu = menu
+            .add_native_item(SystemTrayMenuItem::Separator)
+            .add_item(CustomMenuItem::new("devtools", "Open DevTools"));
+    }
+
+    menu.add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(CustomMenuItem::new("quit", "Quit"))
+}
+
+/// Menu entry for a device, like "Razer Viper Ultimate ⚡️80%"
+fn device_title(device: &TrayDevice, devices: &[TrayDevice]) -> String {
+    let info = &device.info;
+    // tells identical devices apart
+    let name = if devices.iter().filter(|d| d.info.name == info.name).count() > 1 {
+        format!("{} ({})", info.name, info.id)
+    } else {
+        info.name.clone()
+    };
+    match &device.status {
+        Ok(status) => format!("{} {}", name, status),
+        Err(_) => format!("{} ⚠️", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::BatteryData;
+    use crate::device::FakeSource;
+
+    fn tray_devices(source: &FakeSource) -> Vec<TrayDevice> {
+        source
+            .devices()
+            .unwrap()
+            .into_iter()
+            .map(|info| TrayDevice {
+                status: BatteryStatus::read(source, &info),
+                info,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_remaining_while_charging() {
+        let db = Database::open_in_memory().unwrap();
+        let source = FakeSource::new();
+        let mouse = source.connect(1, Some("M1"), "Mouse", 40, true);
+        let status = BatteryStatus::get(&source, &mouse).unwrap().unwrap();
+        assert_eq!(
+            remaining_title(&db, &status),
+            "Not enough data to calculate charging time yet"
+        );
+
+        let start = BatteryData::new(1, "2022-01-01 20:00:00", 0, false).created_at;
+        for (minutes, percentage) in [(0, 30), (3, 31), (6, 32)] {
+            let sample = BatteryStatus {
+                percentage,
+                ..status.clone()
+            };
+            sample
+                .save_at(&db, start + chrono::Duration::minutes(minutes))
+                .unwrap();
+        }
+        assert_eq!(remaining_title(&db, &status), "full in 3h");
+
+        source.set_battery(&mouse, 100, true);
+        let status = BatteryStatus::get(&source, &mouse).unwrap().unwrap();
+        assert_eq!(remaining_title(&db, &status), "Fully charged");
+    }
+
+    #[test]
+    fn test_device_title() {
+        let source = FakeSource::new();
+        source.connect(1, Some("M1"), "Mouse", 80, false);
+        let other_mouse = source.connect(1, Some("M2"), "Mouse", 60, true);
+        let keyboard = source.connect(2, None, "Keyboard", 15, false);
+
+        let devices = tray_devices(&source);
+        assert_eq!(device_title(&devices[0], &devices), "Mouse (M1) 🔋80%");
+        assert_eq!(device_title(&devices[1], &devices), "Mouse (M2) ⚡️60%");
+        assert_eq!(device_title(&devices[2], &devices), "Keyboard 🪫15%");
+
+        source.disconnect(&other_mouse);
+        source.set_failing(&keyboard, true);
+        let devices = tray_devices(&source);
+        assert_eq!(device_title(&devices[0], &devices), "Mouse 🔋80%");
+        assert_eq!(device_title(&devices[1], &devices), "Keyboard ⚠️");
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::device::{DeviceInfo, SharedSource};
+
+/// How often the device list is scanned when no hotplug events are available
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the watcher sleeps between checks for hotplug events
+const EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+#[serde(tag = "type", content = "device", rename_all = "snake_case")]
+pub(crate) enum DeviceEvent {
+    Connected(DeviceInfo),
+    Disconnected(DeviceInfo),
+}
+
+impl DeviceEvent {
+    /// Name of the event emitted to the frontend
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceEvent::Connected(_) => "device-connected",
+            DeviceEvent::Disconnected(_) => "device-disconnected",
+        }
+    }
+
+    pub fn device(&self) -> &DeviceInfo {
+        match self {
+            DeviceEvent::Connected(device) | DeviceEvent::Disconnected(device) => device,
+        }
+    }
+}
+
+/// Keeps track of the connected devices and turns changes to the list into events
+#[derive(Default)]
+pub(crate) struct Watcher {
+    known: Vec<DeviceInfo>,
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn devices(&self) -> &[DeviceInfo] {
+        &self.known
+    }
+
+    /// Compares the current device list with the last one, returning what changed
+    pub fn update(&mut self, devices: Vec<DeviceInfo>) -> Vec<DeviceEvent> {
+        let mut events = vec![];
+        for device in &self.known {
+            if !devices.iter().any(|d| d.id == device.id) {
+                events.push(DeviceEvent::Disconnected(device.clone()));
+            }
+        }
+        for device in &devices {
+            if !self.known.iter().any(|d| d.id == device.id) {
+                events.push(DeviceEvent::Connected(device.clone()));
+            }
+        }
+        self.known = devices;
+        events
+    }
+}
+
+/// Stops a background thread when asked to, or when dropped
+pub(crate) struct StopHandle {
+    stop: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    /// Returns the handle along with the flag the thread should check
+    pub fn new() -> (Self, Arc<AtomicBool>) {
+        let stop = Arc::new(AtomicBool::new(false));
+        (Self { stop: stop.clone() }, stop)
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for StopHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Watches for devices arriving and leaving, calling `on_event` for each change along with the
+/// current device list
+///
+/// On Linux, udev `hidraw` events trigger an immediate scan. Everywhere else, and when udev is
+/// not available, the device list is scanned every few seconds.
+pub(crate) fn spawn(
+    source: SharedSource,
+    mut on_event: impl FnMut(DeviceEvent, &[DeviceInfo]) + Send + 'static,
+) -> StopHandle {
+    let (handle, stopped) = StopHandle::new();
+
+    thread::spawn(move || {
+        let hotplug = Hotplug::new();
+        let mut watcher = Watcher::new();
+        let mut last_scan: Option<Instant> = None;
+        while !stopped.load(Ordering::Relaxed) {
+            let due = match last_scan {
+                Some(last_scan) => last_scan.elapsed() >= POLL_INTERVAL,
+                None => true,
+            };
+            if hotplug.changed() || due {
+                last_scan = Some(Instant::now());
+                match source.devices() {
+                    Ok(devices) => {
+                        for event in watcher.update(devices) {
+                            on_event(event, watcher.devices());
+                        }
+                    }
+                    Err(err) => eprintln!("Error scanning for devices: {}", err),
+                }
+            }
+            thread::sleep(EVENT_INTERVAL);
+        }
+    });
+
+    handle
+}
+
+/// udev monitor for `hidraw` devices
+#[cfg(target_os = "linux")]
+struct Hotplug(Option<udev::MonitorSocket>);
+
+#[cfg(target_os = "linux")]
+impl Hotplug {
+    fn new() -> Self {
+        let socket = udev::MonitorBuilder::new()
+            .and_then(|builder| builder.match_subsystem("hidraw"))
+            .and_then(|builder| builder.listen());
+        match socket {
+            Ok(socket) => Self(Some(socket)),
+            Err(err) => {
+                eprintln!(
+                    "WARN: Couldn't listen to udev events, polling instead: {}",
+                    err
+                );
+                Self(None)
+            }
+        }
+    }
+
+    /// Drains pending events, returning whether there were any
+    fn changed(&self) -> bool {
+        match &self.0 {
+            Some(socket) => socket.iter().count() > 0,
+            None => false,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Hotplug;
+
+#[cfg(not(target_os = "linux"))]
+impl Hotplug {
+    fn new() -> Self {
+        Self
+    }
+
+    fn changed(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{DeviceSource, FakeSource};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_update() {
+        let source = FakeSource::new();
+        let mouse = source.connect(1, Some("M1"), "Mouse", 80, false);
+        let mut watcher = Watcher::new();
+
+        let events = watcher.update(source.devices().unwrap());
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], DeviceEvent::Connected(d) if d.id == mouse));
+        assert!(watcher.update(source.devices().unwrap()).is_empty());
+
+        let keyboard = source.connect(2, None, "Keyboard", 50, false);
+        source.disconnect(&mouse);
+        let events = watcher.update(source.devices().unwrap());
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], DeviceEvent::Disconnected(d) if d.id == mouse));
+        assert!(matches!(&events[1], DeviceEvent::Connected(d) if d.id == keyboard));
+        assert_eq!(watcher.devices().len(), 1);
+    }
+
+    #[test]
+    fn test_spawn() {
+        let source = Arc::new(FakeSource::new());
+        source.connect(1, Some("M1"), "Mouse", 80, false);
+        let (tx, rx) = mpsc::channel();
+        let handle = spawn(source.clone(), move |event, devices| {
+            tx.send((event, devices.len())).unwrap()
+        });
+
+        let (event, connected) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(event.name(), "device-connected");
+        assert_eq!(event.device().id, "M1");
+        assert_eq!(connected, 1);
+
+        handle.stop();
+    }
+}
@@ -1,5 +1,6 @@
 import { Box } from '@chakra-ui/react';
 import { invoke } from '@tauri-apps/api';
+import { listen } from '@tauri-apps/api/event';
 import { DateTime } from 'luxon';
 import { useEffect, useState } from 'react';
 import {
@@ -12,8 +13,10 @@ import {
 } from 'recharts';
 
 interface BatteryData {
+  device_id: string;
   product_id: number;
-  created_at: string | number;
+  // milliseconds since the Unix epoch
+  created_at: number;
   percentage: number;
   charging: boolean;
 }
@@ -40,59 +43,81 @@ const CustomTooltip = ({ active, payload: data }: CustomTooltipProps) => {
 };
 
 export default function Home() {
-  const [productId, setProductId] = useState(null);
+  const [deviceId, setDeviceId] = useState(null);
   const [status, setStatus] = useState(null);
   const [data, setData] = useState<BatteryData[]>([]);
   const [batteryStats, setBatteryStats] = useState({});
+  const [error, setError] = useState<string | null>(null);
 
   useEffect(() => {
-    invoke('selected_product_id')
-      .then((productId: any) => {
-        setProductId(productId);
-      })
-      .catch(console.error);
+    const loadDevice = () =>
+      invoke('selected_device_id')
+        .then((deviceId: any) => {
+          setDeviceId(deviceId);
+        })
+        .catch(setError);
+    loadDevice();
+
+    const unlisten = Promise.all([
+      listen('device-connected', loadDevice),
+      listen('device-disconnected', loadDevice),
+    ]);
+    return () => {
+      unlisten.then((fns) => fns.forEach((fn) => fn()));
+    };
   }, []);
 
   useEffect(() => {
-    if (!productId) return;
+    if (!deviceId) return;
 
-    invoke('device_status', { productId })
+    invoke('device_status', { deviceId })
       .then((status: any) => {
         setStatus(status);
       })
-      .catch(console.error);
+      .catch(setError);
 
-    invoke<BatteryData[]>('charge_history', { productId })
+    invoke<BatteryData[]>('charge_history', { deviceId })
       .then((res: BatteryData[]) => {
         let data = res.filter((d) => d.percentage > 0);
         let values = data
           .filter((d, i) => i == 0 || d.percentage != data[i - 1].percentage)
-          .map((d) => ({
-            ...d,
-            millis: DateTime.fromFormat(
-              d.created_at as string,
-              'yyyy-MM-dd HH:mm:ss',
-              { zone: 'utc' },
-            ).toMillis(),
-          }));
+          .map((d) => ({ ...d, millis: d.created_at }));
         setData(values);
       })
-      .catch(console.error);
+      .catch(setError);
 
-    invoke('battery_stats', { productId })
-      .then(([status, remaining]: any) => {
-        console.log('status/remaining', status, remaining);
-        setBatteryStats({ status, remaining });
+    invoke('battery_stats', { deviceId })
+      .then(([status, remaining, prediction, cycles]: any) => {
+        console.log('status/remaining', status, remaining, prediction, cycles);
+        setBatteryStats({ status, remaining, prediction, cycles });
       })
-      .catch(console.error);
-  }, [productId]);
+      .catch(setError);
+  }, [deviceId]);
+
+  if (error) {
+    return (
+      <Box p={5} color="red.400">
+        {error}
+      </Box>
+    );
+  }
 
   if (!data.length) return null;
 
   return (
     <Box p={5}>
       <div>{(status as any)?.name}</div>
-      <div>{(batteryStats as any)?.remaining}</div>
+      <div>
+        {(batteryStats as any)?.remaining}
+        {(batteryStats as any)?.prediction &&
+          ` (from ${(batteryStats as any).prediction.samples} measurements)`}
+      </div>
+      {(status as any)?.cycles && (
+        <div>
+          {(status as any).cycles.full_cycles.toFixed(1)} full cycles,{' '}
+          {(status as any).cycles.charge_events} charges
+        </div>
+      )}
       <AreaChart width={900} height={400} data={data}>
         <defs>
           <linearGradient id="colorUv" x1="0" y1="0" x2="0" y2="1">